@@ -1,12 +1,17 @@
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
 use std::sync::Arc;
 
 use conduit::{Request, Response};
 use conduit_middleware::Middleware;
+use handlebars::Handlebars;
 use oauth2;
 use r2d2;
 
-use {db, Config};
+use {crypto, db, http, travis, Config};
+use errors::*;
 
 /// The `App` struct holds the main components of the application like
 /// the database connection pool and configurations
@@ -15,6 +20,11 @@ pub struct App {
     pub github: oauth2::Config,
     pub session_key: String,
     pub config: Config,
+    pub travis_keys: travis::KeyCache,
+    pub templates: Handlebars,
+    pub encryption_key: crypto::Key,
+    /// Used for every request to GitHub, Travis and AppVeyor.
+    pub http: Box<http::ApiClient>,
 }
 
 /// The `AppMiddleware` injects an `App` instance into the `Request` extensions
@@ -27,8 +37,8 @@ impl App {
         let mut github = oauth2::Config::new(
             &config.gh_client_id,
             &config.gh_client_secret,
-            "https://github.com/login/oauth/authorize",
-            "https://github.com/login/oauth/access_token",
+            &format!("{}/login/oauth/authorize", config.github_url),
+            &format!("{}/login/oauth/access_token", config.github_url),
         );
 
         // generally useful
@@ -48,15 +58,43 @@ impl App {
             .helper_threads(if config.env == ::Env::Production {3} else {1})
             .build();
 
+        let mut encryption_key = crypto::Key::new(&config.encryption_key)
+                                             .unwrap();
+        if let Some(ref old) = config.old_encryption_key {
+            let old = crypto::Key::new(old).unwrap();
+            encryption_key = encryption_key.with_fallback(old);
+        }
+
         return App {
             database: db::pool(&config.db_url, db_config),
             github: github,
             session_key: config.session_key.clone(),
             config: config.clone(),
+            travis_keys: travis::KeyCache::new(config.travis_endpoints.clone()),
+            templates: load_templates(Path::new("templates")).unwrap(),
+            encryption_key: encryption_key,
+            http: Box::new(http::Curl),
         };
     }
 }
 
+/// Registers every `*.hbs` file in `dir` as a template named after the file,
+/// e.g. `templates/repo.hbs` is the `repo` template.
+fn load_templates(dir: &Path) -> BorsResult<Handlebars> {
+    let mut templates = Handlebars::new();
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.extension().and_then(|s| s.to_str()) != Some("hbs") {
+            continue
+        }
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        let mut contents = String::new();
+        try!(File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)));
+        try!(templates.register_template_string(&name, contents));
+    }
+    Ok(templates)
+}
+
 impl AppMiddleware {
     pub fn new(app: Arc<App>) -> AppMiddleware {
         AppMiddleware { app: app }
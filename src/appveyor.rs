@@ -1,8 +1,21 @@
 #![allow(bad_style)]
 
-#[derive(RustcDecodable)]
+use app::App;
+
+/// The web page of `build`, next to the API at `APPVEYOR_API_URL`.
+pub fn build_url(app: &App, project: &Project, build: &Build) -> String {
+    let api = &app.config.appveyor_api_url;
+    format!("{}/project/{}/{}/build/{}",
+            api.trim_right_matches("/api"),
+            project.accountName,
+            project.slug,
+            build.version)
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
 pub struct Project {
     pub projectId: u32,
+    pub accountName: String,
     pub repositoryType: String,
     pub slug: String,
     pub name: String,
@@ -14,3 +27,42 @@ pub struct NewProject {
     pub repositoryProvider: String,
     pub repositoryName: String,
 }
+
+/// The body of a build notification webhook.
+#[derive(RustcDecodable)]
+pub struct Notification {
+    pub eventName: String,
+    pub eventData: NotificationBuild,
+}
+
+#[derive(RustcDecodable)]
+pub struct NotificationBuild {
+    pub accountName: String,
+    pub projectSlug: String,
+    pub repositoryName: String,
+    pub buildId: u32,
+    pub buildVersion: String,
+    pub branch: String,
+    pub commitId: String,
+    pub status: String,
+    pub passed: bool,
+    pub failed: bool,
+    pub buildUrl: String,
+}
+
+/// A build as fetched from AppVeyor, which is what gets stored for the worker
+/// as notifications themselves can't be trusted.
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct GetBuild {
+    pub project: Project,
+    pub build: Build,
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct Build {
+    pub buildId: u32,
+    pub version: String,
+    pub branch: String,
+    pub commitId: String,
+    pub status: String,
+}
@@ -16,7 +16,6 @@ use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use std::fs::File;
 
-use bors2::env;
 use civet::Server;
 
 fn main() {
@@ -25,29 +24,11 @@ fn main() {
     let addr = addr.parse::<SocketAddr>().unwrap();
 
     let heroku = env::var("HEROKU").is_ok();
-    let host = if heroku {
-        format!("https://bors2-test.herokuapp.com")
-    } else {
-        format!("http://localhost:3000")
-    };
-
-    let bors_env = if heroku {
-        bors2::Env::Production
-    } else {
-        bors2::Env::Development
-    };
-    let config = bors2::Config {
-        session_key: env("SESSION_KEY"),
-        gh_client_id: env("GH_CLIENT_ID"),
-        gh_client_secret: env("GH_CLIENT_SECRET"),
-        db_url: env("DATABASE_URL"),
-        env: bors_env,
-        host: host,
-    };
+    let config = bors2::Config::from_env();
     let app = bors2::app::App::new(&config);
     let app = bors2::middleware(Arc::new(app));
 
-    let threads = if bors_env == bors2::Env::Development {1} else {5};
+    let threads = if config.env == bors2::Env::Development {1} else {5};
     let mut cfg = civet::Config::new();
     cfg.port(addr.port()).threads(threads).keep_alive(true);
     let _a = Server::start(cfg, app).unwrap();
@@ -10,6 +10,7 @@ use std::collections::HashSet;
 use migrate::Migration;
 use postgres::transaction::Transaction;
 
+use bors2::crypto::{self, Key};
 use bors2::env;
 
 #[allow(dead_code)]
@@ -21,11 +22,69 @@ fn main() {
     let arg = env::args().nth(1);
     if arg.as_ref().map(|s| &s[..]) == Some("rollback") {
         rollback(conn.transaction().unwrap(), migrations).unwrap();
+    } else if arg.as_ref().map(|s| &s[..]) == Some("reencrypt") {
+        let key = Key::new(&env("ENCRYPTION_KEY")).unwrap();
+        let old_key = env::var("OLD_ENCRYPTION_KEY").ok().map(|k| {
+            Key::new(&k).unwrap()
+        });
+        reencrypt(conn.transaction().unwrap(), &key, old_key.as_ref(), true);
     } else {
         apply(conn.transaction().unwrap(), migrations).unwrap();
+        // Encrypt any secrets stored before they were encrypted at rest
+        let key = Key::new(&env("ENCRYPTION_KEY")).unwrap();
+        reencrypt(conn.transaction().unwrap(), &key, None, false);
     }
 }
 
+/// Columns holding values encrypted with `ENCRYPTION_KEY`.
+const ENCRYPTED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+    ("projects", "github_access_token"),
+    ("projects", "github_webhook_secret"),
+    ("projects", "travis_access_token"),
+    ("projects", "appveyor_token"),
+    ("users", "github_access_token"),
+];
+
+/// Encrypts every secret in the database with `key`.
+///
+/// Plain text values are always encrypted. If `all` is set then values which
+/// are already encrypted are decrypted, with `key` or else `old_key`, and
+/// encrypted again, which is how the key is rotated.
+fn reencrypt(tx: Transaction, key: &Key, old_key: Option<&Key>, all: bool) {
+    for &(table, column) in ENCRYPTED_COLUMNS {
+        let select = format!("SELECT id, {col} FROM {table}
+                              WHERE {col} IS NOT NULL
+                              FOR UPDATE",
+                             table = table, col = column);
+        let update = format!("UPDATE {table} SET {col} = $1 WHERE id = $2",
+                             table = table, col = column);
+        let update = tx.prepare(&update).unwrap();
+        for row in tx.query(&select, &[]).unwrap().iter() {
+            let id: i32 = row.get(0);
+            let value: String = row.get(1);
+            let plaintext = if !crypto::is_encrypted(&value) {
+                value
+            } else if !all {
+                continue
+            } else {
+                match key.decrypt(&value) {
+                    Ok(plaintext) => plaintext,
+                    Err(e) => {
+                        match old_key {
+                            Some(old) => old.decrypt(&value).unwrap(),
+                            None => panic!("failed to decrypt {}.{} of {}: \
+                                            {}", table, column, id, e),
+                        }
+                    }
+                }
+            };
+            update.execute(&[&key.encrypt(&plaintext).unwrap(), &id]).unwrap();
+        }
+    }
+    tx.set_commit();
+    tx.finish().unwrap();
+}
+
 fn apply(tx: Transaction,
          migrations: Vec<Migration>) -> postgres::Result<()> {
     let mut mgr = try!(migrate::Manager::new(tx));
@@ -50,6 +109,10 @@ fn rollback(tx: Transaction,
 }
 
 fn migrations() -> Vec<Migration> {
+    // Projects registered again later on with the same GitHub repository
+    let duplicates = "SELECT a.id FROM projects a, projects b
+                      WHERE a.github_repo_id = b.github_repo_id
+                        AND a.id < b.id";
     let migrations = vec![
         Migration::add_table(20161030140653, "projects", "
             id                      SERIAL PRIMARY KEY,
@@ -71,21 +134,152 @@ fn migrations() -> Vec<Migration> {
             state                   INTEGER NOT NULL,
             processed_at            TIMESTAMP NOT NULL default now()
         "),
-        // Migration::add_table(20161030140653, "pull_requests", "
-        //     id          SERIAL PRIMARY KEY,
-        //     number      INTEGER NOT NULL,
-        //     github_id   INTEGER NOT NULL,
-        //     status      INTEGER NOT NULL,
-        //     head_ref    VARCHAR NOT NULL,
-        //     head_commit VARCHAR NOT NULL,
-        //     title       VARCHAR NOT NULL,
-        //     approved_by VARCHAR,
-        //     mergeable   BOOLEAN NOT NULL,
-        //     assignee    VARCHAR,
-        //     priority    INTEGER NOT NULL,
-        //     rollup      BOOLEAN NOT NULL,
-        //     created_at  TIMESTAMP NOT NULL DEFAULT now()
-        // "),
+        Migration::add_column(20161105093412, "events", "project_id",
+                              "INTEGER"),
+        Migration::add_table(20161106141523, "pull_requests", "
+            id              SERIAL PRIMARY KEY,
+            project_id      INTEGER NOT NULL,
+            number          INTEGER NOT NULL,
+            github_id       BIGINT NOT NULL,
+            status          INTEGER NOT NULL,
+            head_ref        VARCHAR NOT NULL,
+            head_commit     VARCHAR NOT NULL,
+            title           VARCHAR NOT NULL,
+            author          VARCHAR NOT NULL,
+            approved_by     VARCHAR,
+            delegate        VARCHAR,
+            mergeable       BOOLEAN,
+            assignee        VARCHAR,
+            priority        INTEGER NOT NULL,
+            rollup          BOOLEAN NOT NULL,
+            try_requested   BOOLEAN NOT NULL,
+            open            BOOLEAN NOT NULL DEFAULT true,
+            created_at      TIMESTAMP NOT NULL DEFAULT now()
+        "),
+        Migration::run(20161107201103,
+                       "CREATE UNIQUE INDEX index_pull_requests_project_number
+                        ON pull_requests (project_id, number)",
+                       "DROP INDEX index_pull_requests_project_number"),
+        Migration::add_column(20161112153047, "pull_requests", "base_ref",
+                              "VARCHAR NOT NULL DEFAULT 'master'"),
+        Migration::add_column(20161112153048, "pull_requests", "merge_sha",
+                              "VARCHAR"),
+        Migration::add_table(20161112153049, "builds", "
+            id                      SERIAL PRIMARY KEY,
+            pull_request_id         INTEGER NOT NULL,
+            provider_id             INTEGER NOT NULL,
+            sha                     VARCHAR NOT NULL,
+            status                  INTEGER NOT NULL,
+            url                     VARCHAR,
+            created_at              TIMESTAMP NOT NULL DEFAULT now(),
+            UNIQUE (pull_request_id, provider_id, sha)
+        "),
+        Migration::add_column(20161119110315, "pull_requests", "try_status",
+                              "INTEGER NOT NULL DEFAULT 0"),
+        Migration::add_column(20161119110316, "pull_requests", "try_sha",
+                              "VARCHAR"),
+        Migration::add_column(20161126164208, "pull_requests", "rollup_id",
+                              "INTEGER"),
+        Migration::add_table(20161203102517, "users", "
+            id                      SERIAL PRIMARY KEY,
+            github_id               INTEGER NOT NULL UNIQUE,
+            login                   VARCHAR NOT NULL,
+            github_access_token     VARCHAR NOT NULL,
+            created_at              TIMESTAMP NOT NULL DEFAULT now()
+        "),
+        Migration::add_table(20161204113021, "reviewers", "
+            id                      SERIAL PRIMARY KEY,
+            project_id              INTEGER NOT NULL,
+            login                   VARCHAR NOT NULL,
+            permission              INTEGER NOT NULL
+        "),
+        // GitHub logins are case insensitive
+        Migration::run(20161204113022,
+                       "CREATE UNIQUE INDEX index_reviewers_project_login
+                        ON reviewers (project_id, lower(login))",
+                       "DROP INDEX index_reviewers_project_login"),
+        // Travis events used to be stored without an id
+        Migration::run(20161210143307,
+                       "UPDATE events SET provider_event_id = 'legacy-' || id
+                        WHERE provider_event_id = ''",
+                       "SELECT 1"),
+        // Irreversible: the duplicates deleted here can't be brought back
+        Migration::run(20161210143308,
+                       "DELETE FROM events a USING events b
+                        WHERE a.project_id IS NOT DISTINCT FROM b.project_id
+                          AND a.provider_id = b.provider_id
+                          AND a.provider_event_id = b.provider_event_id
+                          AND a.id > b.id",
+                       "SELECT 1"),
+        // Build ids are only unique per Travis instance, and projects can be
+        // on either travis-ci.org or travis-ci.com
+        Migration::run(20161210143309,
+                       "CREATE UNIQUE INDEX index_events_project_provider_event
+                        ON events (project_id, provider_id, provider_event_id)",
+                       "DROP INDEX index_events_project_provider_event"),
+        // Events which haven't been processed (again) have no processed_at
+        Migration::run(20161211160432,
+                       "ALTER TABLE events
+                        ALTER COLUMN processed_at DROP NOT NULL,
+                        ALTER COLUMN processed_at DROP DEFAULT",
+                       "ALTER TABLE events
+                        ALTER COLUMN processed_at SET DEFAULT now(),
+                        ALTER COLUMN processed_at SET NOT NULL"),
+        Migration::run(20161211160433,
+                       "UPDATE events SET processed_at = NULL WHERE state = 0",
+                       "UPDATE events SET processed_at = created_at
+                        WHERE processed_at IS NULL"),
+        Migration::add_column(20161215093015, "events", "retry_at",
+                              "TIMESTAMP"),
+        Migration::add_column(20161217153210, "projects", "github_webhook_id",
+                              "INTEGER"),
+        Migration::add_column(20161218101544, "projects",
+                              "github_webhook_status", "VARCHAR"),
+        // Re-adding a repository used to register it again, keep the latest
+        // registration. Events are moved over to it while the older
+        // registrations' pull requests and reviewers are deleted along with
+        // them, like `Project::delete` does. Events which a later
+        // registration has as well are dropped first so the moved ones stay
+        // unique per project. Irreversible.
+        Migration::run(20161224112625,
+                       "DELETE FROM events a
+                        USING events b, projects pa, projects pb
+                        WHERE a.project_id = pa.id
+                          AND b.project_id = pb.id
+                          AND pa.github_repo_id = pb.github_repo_id
+                          AND pa.id < pb.id
+                          AND a.provider_id = b.provider_id
+                          AND a.provider_event_id = b.provider_event_id",
+                       "SELECT 1"),
+        Migration::run(20161224112626,
+                       &format!("UPDATE events e SET project_id =
+                                   (SELECT max(b.id) FROM projects a
+                                      JOIN projects b
+                                        ON a.github_repo_id = b.github_repo_id
+                                     WHERE a.id = e.project_id)
+                                 WHERE project_id IN ({})", duplicates),
+                       "SELECT 1"),
+        Migration::run(20161224112627,
+                       &format!("DELETE FROM builds WHERE pull_request_id IN
+                                   (SELECT id FROM pull_requests
+                                     WHERE project_id IN ({}))", duplicates),
+                       "SELECT 1"),
+        Migration::run(20161224112628,
+                       &format!("DELETE FROM pull_requests
+                                 WHERE project_id IN ({})", duplicates),
+                       "SELECT 1"),
+        Migration::run(20161224112629,
+                       &format!("DELETE FROM reviewers
+                                 WHERE project_id IN ({})", duplicates),
+                       "SELECT 1"),
+        Migration::run(20161224112630,
+                       &format!("DELETE FROM projects
+                                 WHERE id IN ({})", duplicates),
+                       "SELECT 1"),
+        Migration::run(20161224112631,
+                       "CREATE UNIQUE INDEX index_projects_github_repo_id
+                        ON projects (github_repo_id)",
+                       "DROP INDEX index_projects_github_repo_id"),
     ];
     // NOTE: Generate a new id via `date +"%Y%m%d%H%M%S"`
 
@@ -1,2 +1,10 @@
+extern crate bors2;
+extern crate env_logger;
+
 fn main() {
+    env_logger::init().unwrap();
+
+    let config = bors2::Config::from_env();
+    let app = bors2::app::App::new(&config);
+    bors2::worker::run(&app);
 }
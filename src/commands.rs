@@ -0,0 +1,186 @@
+//! Parsing of homu-style commands such as `@bors r+` out of comments.
+//!
+//! A command line starts with a mention of the bot, and every word after the
+//! mention on that line is interpreted as a command. Unknown words are
+//! ignored, as are quoted lines so replies don't re-run old commands.
+
+use models::{Permission, PullRequest, Status};
+
+/// The name the bot answers to in comments.
+pub const BOT_NAME: &'static str = "bors";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// `r+` approves on behalf of the commenter, `r=user` on behalf of `user`.
+    Approve(Option<String>),
+    /// `r-`
+    Unapprove,
+    /// `p=N` or `priority=N`
+    Priority(i32),
+    /// `rollup` and `rollup-`
+    Rollup(bool),
+    /// `retry`
+    Retry,
+    /// `try`
+    Try,
+    /// `delegate+`, allowing the author of the PR to approve it.
+    Delegate,
+    /// `delegate-`
+    Undelegate,
+}
+
+/// Returns all commands addressed to the bot in `body`, in order.
+pub fn parse(body: &str) -> Vec<Command> {
+    let mention = format!("@{}", BOT_NAME);
+    let mut commands = Vec::new();
+    for line in body.lines() {
+        if line.trim_left().starts_with('>') {
+            continue
+        }
+        let mut words = line.split_whitespace().skip_while(|w| {
+            w.trim_right_matches(|c: char| c == ':' || c == ',') != mention
+        });
+        if words.next().is_none() {
+            continue
+        }
+        commands.extend(words.filter_map(parse_word));
+    }
+    commands
+}
+
+fn parse_word(word: &str) -> Option<Command> {
+    match word {
+        "r+" => return Some(Command::Approve(None)),
+        "r-" => return Some(Command::Unapprove),
+        "rollup" | "rollup+" => return Some(Command::Rollup(true)),
+        "rollup-" => return Some(Command::Rollup(false)),
+        "retry" => return Some(Command::Retry),
+        "try" => return Some(Command::Try),
+        "delegate+" => return Some(Command::Delegate),
+        "delegate-" => return Some(Command::Undelegate),
+        _ => {}
+    }
+
+    let mut parts = word.splitn(2, '=');
+    let key = parts.next().unwrap();
+    let value = match parts.next() {
+        Some(value) if !value.is_empty() => value,
+        _ => return None,
+    };
+    match key {
+        "r" => Some(Command::Approve(Some(value.trim_left_matches('@')
+                                               .to_string()))),
+        "p" | "priority" => value.parse().ok().map(Command::Priority),
+        _ => None,
+    }
+}
+
+impl Command {
+    /// Returns whether someone with `permission` on the project may issue
+    /// this command. `delegated` is whether the pull request has been
+    /// delegated to them with `delegate+`.
+    pub fn permitted(&self,
+                     permission: Option<Permission>,
+                     delegated: bool) -> bool {
+        match *self {
+            Command::Try => permission.is_some() || delegated,
+            Command::Delegate |
+            Command::Undelegate => permission == Some(Permission::Review),
+            _ => permission == Some(Permission::Review) || delegated,
+        }
+    }
+
+    /// Applies this command, issued by `user`, to the state of `pr`.
+    pub fn apply(&self, pr: &mut PullRequest, user: &str) {
+        match *self {
+            Command::Approve(ref reviewer) => {
+                let reviewer = reviewer.as_ref().map(|s| &s[..]).unwrap_or(user);
+                pr.approved_by = Some(reviewer.to_string());
+            }
+            Command::Unapprove => {
+                pr.approved_by = None;
+                // Keep a build in progress from landing
+                if pr.status == Status::Pending {
+                    pr.status = Status::Idle;
+                }
+            }
+            Command::Priority(priority) => pr.priority = priority,
+            Command::Rollup(rollup) => pr.rollup = rollup,
+            Command::Retry => pr.status = Status::Idle,
+            Command::Try => pr.try_requested = true,
+            Command::Delegate => pr.delegate = Some(pr.author.clone()),
+            Command::Undelegate => pr.delegate = None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use models::Permission;
+    use super::{parse, Command};
+
+    #[test]
+    fn no_mention() {
+        assert_eq!(parse("r+"), vec![]);
+        assert_eq!(parse("bors r+"), vec![]);
+        assert_eq!(parse("@borsbot r+"), vec![]);
+    }
+
+    #[test]
+    fn approve() {
+        assert_eq!(parse("@bors r+"), vec![Command::Approve(None)]);
+        assert_eq!(parse("@bors: r+"), vec![Command::Approve(None)]);
+        assert_eq!(parse("@bors r=alice"),
+                   vec![Command::Approve(Some("alice".to_string()))]);
+        assert_eq!(parse("@bors r=@alice"),
+                   vec![Command::Approve(Some("alice".to_string()))]);
+        assert_eq!(parse("@bors r="), vec![]);
+        assert_eq!(parse("@bors r-"), vec![Command::Unapprove]);
+    }
+
+    #[test]
+    fn several_commands() {
+        assert_eq!(parse("looks good!\n@bors r+ p=2 rollup\nthanks"),
+                   vec![Command::Approve(None),
+                        Command::Priority(2),
+                        Command::Rollup(true)]);
+        assert_eq!(parse("@bors retry\n@bors try"),
+                   vec![Command::Retry, Command::Try]);
+        assert_eq!(parse("@bors delegate+ rollup- priority=-1"),
+                   vec![Command::Delegate,
+                        Command::Rollup(false),
+                        Command::Priority(-1)]);
+    }
+
+    #[test]
+    fn words_before_mention_ignored() {
+        assert_eq!(parse("retry @bors r+"), vec![Command::Approve(None)]);
+    }
+
+    #[test]
+    fn unknown_words_ignored() {
+        assert_eq!(parse("@bors please r+"), vec![Command::Approve(None)]);
+        assert_eq!(parse("@bors p=high"), vec![]);
+    }
+
+    #[test]
+    fn quotes_ignored() {
+        assert_eq!(parse("> @bors r+\nwhy?"), vec![]);
+    }
+
+    #[test]
+    fn permissions() {
+        let approve = Command::Approve(None);
+        assert!(approve.permitted(Some(Permission::Review), false));
+        assert!(approve.permitted(None, true));
+        assert!(!approve.permitted(Some(Permission::Try), false));
+        assert!(!approve.permitted(None, false));
+
+        assert!(Command::Try.permitted(Some(Permission::Try), false));
+        assert!(Command::Try.permitted(None, true));
+        assert!(!Command::Try.permitted(None, false));
+
+        assert!(Command::Delegate.permitted(Some(Permission::Review), false));
+        assert!(!Command::Delegate.permitted(None, true));
+    }
+}
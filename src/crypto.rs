@@ -0,0 +1,138 @@
+//! Encryption of the access tokens and secrets stored in the database.
+//!
+//! Values are encrypted with AES-256-CBC under a random IV, and the IV and
+//! ciphertext are then authenticated with HMAC-SHA256. Both keys are derived
+//! from the `ENCRYPTION_KEY` secret. An encrypted value is stored as `v1:`
+//! followed by the hex of the IV, ciphertext and MAC.
+//!
+//! While the key is being rotated values may still be encrypted with the
+//! previous key, `OLD_ENCRYPTION_KEY`, which decryption falls back to.
+
+use std::str;
+
+use openssl::crypto::hash::{self, Type};
+use openssl::crypto::{hmac, memcmp, symm};
+use rand::{OsRng, Rng};
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use errors::*;
+
+const PREFIX: &'static str = "v1:";
+const IV_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+pub struct Key {
+    encrypt: Vec<u8>,
+    mac: Vec<u8>,
+    fallback: Option<Box<Key>>,
+}
+
+impl Key {
+    pub fn new(secret: &str) -> BorsResult<Key> {
+        let encrypt = format!("encrypt:{}", secret);
+        let mac = format!("mac:{}", secret);
+        Ok(Key {
+            encrypt: try!(hash::hash(Type::SHA256, encrypt.as_bytes())),
+            mac: try!(hash::hash(Type::SHA256, mac.as_bytes())),
+            fallback: None,
+        })
+    }
+
+    /// Makes `decrypt` try `old` for values this key can't decrypt.
+    pub fn with_fallback(mut self, old: Key) -> Key {
+        self.fallback = Some(Box::new(old));
+        self
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> BorsResult<String> {
+        let mut iv = [0; IV_LEN];
+        try!(OsRng::new()).fill_bytes(&mut iv);
+        let ciphertext = try!(symm::encrypt(symm::Type::AES_256_CBC,
+                                            &self.encrypt,
+                                            Some(&iv),
+                                            plaintext.as_bytes()));
+        let mut data = iv.to_vec();
+        data.extend(ciphertext);
+        let mac = try!(hmac::hmac(Type::SHA256, &self.mac, &data));
+        data.extend(mac);
+        Ok(format!("{}{}", PREFIX, data.to_hex()))
+    }
+
+    pub fn decrypt(&self, value: &str) -> BorsResult<String> {
+        match self.decrypt_own(value) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(e) => {
+                match self.fallback {
+                    Some(ref old) => old.decrypt(value).or(Err(e)),
+                    None => Err(e),
+                }
+            }
+        }
+    }
+
+    fn decrypt_own(&self, value: &str) -> BorsResult<String> {
+        if !is_encrypted(value) {
+            return Err("value is not encrypted".into())
+        }
+        let data = try!(value[PREFIX.len()..].from_hex());
+        if data.len() < IV_LEN + MAC_LEN {
+            return Err("encrypted value is too short".into())
+        }
+        let (data, mac) = data.split_at(data.len() - MAC_LEN);
+        let expected = try!(hmac::hmac(Type::SHA256, &self.mac, data));
+        if !memcmp::eq(mac, &expected) {
+            return Err("encrypted value failed authentication".into())
+        }
+        let (iv, ciphertext) = data.split_at(IV_LEN);
+        let plaintext = try!(symm::decrypt(symm::Type::AES_256_CBC,
+                                           &self.encrypt,
+                                           Some(iv),
+                                           ciphertext));
+        Ok(try!(str::from_utf8(&plaintext)).to_string())
+    }
+}
+
+/// Returns whether `value` was produced by `Key::encrypt`, as opposed to
+/// being a plain text value stored before encryption was introduced.
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_encrypted, Key};
+
+    #[test]
+    fn round_trip() {
+        let key = Key::new("sekrit").unwrap();
+        let value = key.encrypt("token").unwrap();
+        assert!(is_encrypted(&value));
+        assert!(!value.contains("token"));
+        assert_eq!(key.decrypt(&value).unwrap(), "token");
+        assert!(key.encrypt("token").unwrap() != value);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let value = Key::new("sekrit").unwrap().encrypt("token").unwrap();
+        assert!(Key::new("other").unwrap().decrypt(&value).is_err());
+    }
+
+    #[test]
+    fn old_key() {
+        let value = Key::new("old").unwrap().encrypt("token").unwrap();
+        let key = Key::new("new").unwrap()
+                                 .with_fallback(Key::new("old").unwrap());
+        assert_eq!(key.decrypt(&value).unwrap(), "token");
+        let value = key.encrypt("token").unwrap();
+        assert!(Key::new("old").unwrap().decrypt(&value).is_err());
+        assert!(Key::new("new").unwrap().decrypt(&value).is_ok());
+    }
+
+    #[test]
+    fn plain_text_rejected() {
+        let key = Key::new("sekrit").unwrap();
+        assert!(key.decrypt("token").is_err());
+        assert!(key.decrypt("v1:00").is_err());
+    }
+}
@@ -3,6 +3,7 @@ use std::str;
 
 use openssl;
 use curl;
+use handlebars;
 use rustc_serialize::json;
 use rustc_serialize::hex;
 use pg;
@@ -15,15 +16,44 @@ error_chain! {
     foreign_links {
         curl::Error, Curl;
         json::DecoderError, Json;
+        json::EncoderError, JsonEncode;
+        json::ParserError, JsonParse;
         str::Utf8Error, NotUtf8;
         pg::error::Error, PostgresError;
         io::Error, Io;
         openssl::error::ErrorStack, Crypto;
         hex::FromHexError, Hex;
+        handlebars::TemplateError, Template;
+        handlebars::RenderError, Render;
     }
 
     errors {
         MissingProject {
         }
+        NotLoggedIn {
+        }
+        Forbidden {
+        }
+        BadRequest(msg: String) {
+            description("bad request")
+            display("bad request: {}", msg)
+        }
+        InvalidSignature {
+            description("missing or invalid webhook signature")
+        }
+        Unauthorized {
+            description("credentials were rejected by the api")
+        }
+        NotFound {
+            description("api resource not found")
+        }
+        RateLimited(retry_after: u64) {
+            description("api rate limit exceeded")
+            display("api rate limit exceeded, resets in {}s", retry_after)
+        }
+        ApiError(status: u32, body: String) {
+            description("api request failed")
+            display("api request failed with a {}: {}", status, body)
+        }
     }
 }
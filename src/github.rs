@@ -1,5 +1,10 @@
 use std::collections::HashMap;
 
+use rustc_serialize::{json, Decodable, Decoder, Encodable, Encoder};
+
+use errors::*;
+use models::{Event, Provider};
+
 #[derive(RustcDecodable, Debug)]
 pub struct Webhook {
     pub id: i32,
@@ -7,11 +12,20 @@ pub struct Webhook {
     pub name: String,
     pub events: Vec<String>,
     pub active: bool,
-    pub config: HashMap<String, String>,
+    pub config: HashMap<String, json::Json>,
+    pub last_response: Option<WebhookResponse>,
     pub updated_at: String,
     pub created_at: String,
 }
 
+/// The outcome of a webhook's latest delivery.
+#[derive(RustcDecodable, Debug)]
+pub struct WebhookResponse {
+    pub code: Option<i32>,
+    pub status: String,
+    pub message: Option<String>,
+}
+
 #[derive(RustcEncodable)]
 pub struct CreateWebhook {
     pub name: String,
@@ -27,6 +41,18 @@ pub struct CreateWebhookConfig {
     pub secret: String,
 }
 
+#[derive(RustcEncodable)]
+pub struct CreateAccessToken {
+    pub client_id: String,
+    pub client_secret: String,
+    pub code: String,
+}
+
+#[derive(RustcDecodable)]
+pub struct AccessToken {
+    pub access_token: String,
+}
+
 #[derive(RustcEncodable)]
 pub struct CreateAuthorization {
     pub scopes: Vec<String>,
@@ -45,4 +71,351 @@ pub struct Authorization {
 pub struct Repository {
     pub id: i32,
     pub name: String,
+    /// The authenticated user's access to this repository.
+    pub permissions: Option<Permissions>,
+}
+
+#[derive(RustcDecodable)]
+pub struct Permissions {
+    pub admin: bool,
+    pub push: bool,
+    pub pull: bool,
+}
+
+#[derive(RustcDecodable)]
+pub struct Collaborator {
+    pub login: String,
+    pub permissions: Option<Permissions>,
+}
+
+#[derive(RustcDecodable)]
+pub struct Team {
+    pub id: i32,
+    pub slug: String,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct User {
+    pub id: i32,
+    pub login: String,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct PullRequest {
+    pub id: i64,
+    pub number: i32,
+    pub state: String,
+    pub title: String,
+    pub user: User,
+    pub head: PullRequestRef,
+    pub base: PullRequestRef,
+    pub mergeable: Option<bool>,
+    pub assignee: Option<User>,
+}
+
+#[derive(Debug)]
+pub struct PullRequestRef {
+    pub label: String,
+    pub ref_: String,
+    pub sha: String,
+}
+
+// `ref` is a keyword, so it can't be a field name. Structs with a `ref_`
+// field instead, like this one, implement `Decodable` or `Encodable` by hand
+// to map it to `ref`.
+impl Decodable for PullRequestRef {
+    fn decode<D: Decoder>(d: &mut D) -> Result<PullRequestRef, D::Error> {
+        d.read_struct("PullRequestRef", 3, |d| {
+            Ok(PullRequestRef {
+                label: try!(d.read_struct_field("label", 0, Decodable::decode)),
+                ref_: try!(d.read_struct_field("ref", 1, Decodable::decode)),
+                sha: try!(d.read_struct_field("sha", 2, Decodable::decode)),
+            })
+        })
+    }
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Issue {
+    pub number: i32,
+    pub title: String,
+    pub user: User,
+    pub pull_request: Option<IssuePullRequest>,
+}
+
+/// Only present on issues which are actually pull requests
+#[derive(RustcDecodable, Debug)]
+pub struct IssuePullRequest {
+    pub url: String,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Comment {
+    pub id: i64,
+    pub body: String,
+    pub user: User,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Review {
+    pub id: i64,
+    pub user: User,
+    pub body: Option<String>,
+    pub state: String,
+}
+
+/// A typed webhook payload, see `Payload::from_event`.
+#[derive(Debug)]
+pub enum Payload {
+    PullRequest(PullRequestEvent),
+    IssueComment(IssueCommentEvent),
+    PullRequestReview(PullRequestReviewEvent),
+    PullRequestReviewComment(PullRequestReviewCommentEvent),
+    Status(StatusEvent),
+    Issues(IssuesEvent),
+    Push(PushEvent),
+    Repository(RepositoryEvent),
+    /// An event we don't have a payload type for, with its name.
+    Other(String),
+}
+
+impl Payload {
+    /// Decodes the payload of a stored GitHub webhook event.
+    pub fn from_event(event: &Event) -> BorsResult<Payload> {
+        if event.provider_id != Provider::GitHub {
+            return Err(format!("event {} is not from github", event.id).into())
+        }
+        let body = &event.event;
+        let payload = match &event.provider_event[..] {
+            "pull_request" => Payload::PullRequest(try!(json::decode(body))),
+            "issue_comment" => Payload::IssueComment(try!(json::decode(body))),
+            "pull_request_review" => {
+                Payload::PullRequestReview(try!(json::decode(body)))
+            }
+            "pull_request_review_comment" => {
+                Payload::PullRequestReviewComment(try!(json::decode(body)))
+            }
+            "status" => Payload::Status(try!(json::decode(body))),
+            "issues" => Payload::Issues(try!(json::decode(body))),
+            "push" => Payload::Push(try!(json::decode(body))),
+            "repository" => Payload::Repository(try!(json::decode(body))),
+            other => Payload::Other(other.to_string()),
+        };
+        Ok(payload)
+    }
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct PullRequestEvent {
+    pub action: String,
+    pub number: i32,
+    pub pull_request: PullRequest,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct IssueCommentEvent {
+    pub action: String,
+    pub issue: Issue,
+    pub comment: Comment,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct PullRequestReviewEvent {
+    pub action: String,
+    pub review: Review,
+    pub pull_request: PullRequest,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct PullRequestReviewCommentEvent {
+    pub action: String,
+    pub pull_request: PullRequest,
+    pub comment: Comment,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct StatusEvent {
+    pub id: i64,
+    pub sha: String,
+    pub state: String,
+    pub context: String,
+    pub description: Option<String>,
+    pub target_url: Option<String>,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct IssuesEvent {
+    pub action: String,
+    pub issue: Issue,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct RepositoryEvent {
+    pub action: String,
+    pub repository: EventRepository,
+}
+
+/// A repository as described in webhook payloads.
+#[derive(RustcDecodable, Debug)]
+pub struct EventRepository {
+    pub id: i32,
+    pub name: String,
+    pub owner: User,
+}
+
+#[derive(Debug)]
+pub struct PushEvent {
+    pub ref_: String,
+    pub before: String,
+    pub after: String,
+    pub forced: bool,
+}
+
+impl Decodable for PushEvent {
+    fn decode<D: Decoder>(d: &mut D) -> Result<PushEvent, D::Error> {
+        d.read_struct("PushEvent", 4, |d| {
+            Ok(PushEvent {
+                ref_: try!(d.read_struct_field("ref", 0, Decodable::decode)),
+                before: try!(d.read_struct_field("before", 1, Decodable::decode)),
+                after: try!(d.read_struct_field("after", 2, Decodable::decode)),
+                forced: try!(d.read_struct_field("forced", 3, Decodable::decode)),
+            })
+        })
+    }
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct GitRef {
+    pub object: GitObject,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct GitObject {
+    pub sha: String,
+}
+
+pub struct CreateRef {
+    pub ref_: String,
+    pub sha: String,
+}
+
+impl Encodable for CreateRef {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("CreateRef", 2, |s| {
+            try!(s.emit_struct_field("ref", 0, |s| self.ref_.encode(s)));
+            s.emit_struct_field("sha", 1, |s| self.sha.encode(s))
+        })
+    }
+}
+
+#[derive(RustcEncodable)]
+pub struct UpdateRef {
+    pub sha: String,
+    pub force: bool,
+}
+
+#[derive(RustcEncodable)]
+pub struct CreateMerge {
+    pub base: String,
+    pub head: String,
+    pub commit_message: String,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct Commit {
+    pub sha: String,
+}
+
+#[derive(RustcEncodable)]
+pub struct CreateComment {
+    pub body: String,
+}
+
+#[derive(RustcEncodable)]
+pub struct CreateStatus {
+    pub state: String,
+    pub target_url: String,
+    pub description: String,
+    pub context: String,
+}
+
+#[derive(RustcDecodable, Debug)]
+pub struct CommitStatus {
+    pub id: i64,
+    pub state: String,
+    pub context: String,
+}
+
+#[derive(RustcEncodable)]
+pub struct CreatePullRequest {
+    pub title: String,
+    pub head: String,
+    pub base: String,
+    pub body: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use models::{Event, Provider, State};
+    use super::Payload;
+
+    fn event(provider_event: &str, payload: &str) -> Event {
+        Event {
+            id: 1,
+            project_id: Some(1),
+            provider_id: Provider::GitHub,
+            provider_event_id: "delivery-1".to_string(),
+            provider_event: provider_event.to_string(),
+            event: payload.to_string(),
+            state: State::Unprocessed,
+        }
+    }
+
+    #[test]
+    fn pull_request() {
+        let payload = include_str!("../tests/fixtures/pull_request.json");
+        match Payload::from_event(&event("pull_request", payload)).unwrap() {
+            Payload::PullRequest(e) => {
+                assert_eq!(e.action, "opened");
+                assert_eq!(e.pull_request.number, 1);
+                assert_eq!(e.pull_request.head.ref_, "readme");
+                assert_eq!(e.pull_request.base.ref_, "master");
+            }
+            other => panic!("wrong payload: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn issue_comment() {
+        let payload = include_str!("../tests/fixtures/issue_comment.json");
+        match Payload::from_event(&event("issue_comment", payload)).unwrap() {
+            Payload::IssueComment(e) => {
+                assert_eq!(e.issue.number, 1);
+                assert!(e.issue.pull_request.is_some());
+                assert_eq!(e.comment.body, "@bors r+");
+                assert_eq!(e.comment.user.login, "bob");
+            }
+            other => panic!("wrong payload: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn push() {
+        let payload = include_str!("../tests/fixtures/push.json");
+        match Payload::from_event(&event("push", payload)).unwrap() {
+            Payload::Push(e) => {
+                assert_eq!(e.ref_, "refs/heads/master");
+                assert!(!e.forced);
+            }
+            other => panic!("wrong payload: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn other() {
+        match Payload::from_event(&event("ping", "{}")).unwrap() {
+            Payload::Other(name) => assert_eq!(name, "ping"),
+            other => panic!("wrong payload: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,161 @@
+//! The webhook bors2 registers on every project's GitHub repository.
+//!
+//! Hooks get deleted by accident or disabled by GitHub after too many failed
+//! deliveries, after which bors2 silently stops hearing about the project.
+//! `check` finds such hooks and puts them back the way `create` made them.
+
+use pg::GenericConnection;
+
+use app::App;
+use errors::*;
+use github;
+use http;
+use models::Project;
+
+/// Creates the webhook on `user/repo`, returning its id.
+pub fn create(app: &App,
+              token: &str,
+              user: &str,
+              repo: &str,
+              secret: &str) -> BorsResult<i32> {
+    let url = format!("/repos/{}/{}/hooks", user, repo);
+    let webhook = config(app, user, repo, secret);
+    let w: github::Webhook = try!(http::github_post(app, &url, &token,
+                                                    &webhook));
+    Ok(w.id)
+}
+
+/// The url GitHub delivers a project's events to.
+pub fn url(app: &App, user: &str, repo: &str) -> String {
+    format!("{}/webhook/github/{}/{}", app.config.host, user, repo)
+}
+
+/// Looks for the project's webhook among the repository's hooks, by its id
+/// or, for projects added before the id was stored, by its url.
+pub fn find(app: &App,
+            project: &Project) -> BorsResult<Option<github::Webhook>> {
+    let url = format!("/repos/{}/{}/hooks", project.repo_user,
+                      project.repo_name);
+    let hooks: Vec<github::Webhook> = try!(http::github_get_all(
+        app,
+        &url,
+        &project.github_access_token));
+    let ours = self::url(app, &project.repo_user, &project.repo_name);
+    Ok(hooks.into_iter().find(|h| {
+        let url = h.config.get("url").and_then(|u| u.as_string());
+        Some(h.id) == project.github_webhook_id || url == Some(&ours[..])
+    }))
+}
+
+/// Makes sure the project's webhook exists and is configured the way
+/// `create` left it, re-creating or fixing it up if not.
+///
+/// The outcome and the status of the hook's latest delivery are stored on
+/// the project to be shown on its page, as is the error if the check fails.
+pub fn check(app: &App,
+             conn: &GenericConnection,
+             project: &Project) -> BorsResult<()> {
+    match repair(app, project) {
+        Ok((id, status)) => project.set_github_webhook(conn, id, &status),
+        Err(e) => {
+            let status = format!("check failed: {}", e);
+            try!(project.set_github_webhook_status(conn, &status));
+            Err(e)
+        }
+    }
+}
+
+/// Does the work of `check`, returning the hook's id and status.
+fn repair(app: &App, project: &Project) -> BorsResult<(i32, String)> {
+    let expected = config(app, &project.repo_user, &project.repo_name,
+                          &project.github_webhook_secret);
+    let (id, status) = match try!(find(app, project)) {
+        None => {
+            info!("creating webhook of {}/{}", project.repo_user,
+                  project.repo_name);
+            let id = try!(create(app,
+                                 &project.github_access_token,
+                                 &project.repo_user,
+                                 &project.repo_name,
+                                 &project.github_webhook_secret));
+            let status = if project.github_webhook_id.is_some() {
+                "webhook was missing and has been re-created"
+            } else {
+                "webhook created"
+            };
+            (id, status.to_string())
+        }
+        // A hook only found by its url may have been created with another
+        // secret, e.g. by an earlier registration of the repository.
+        Some(ref hook) if Some(hook.id) != project.github_webhook_id ||
+                          !matches(hook, &expected) => {
+            info!("repairing webhook of {}/{}", project.repo_user,
+                  project.repo_name);
+            let url = format!("/repos/{}/{}/hooks/{}", project.repo_user,
+                              project.repo_name, hook.id);
+            let w: github::Webhook = try!(http::github_patch(
+                app,
+                &url,
+                &project.github_access_token,
+                &expected));
+            (w.id, format!("webhook has been updated, last delivery: {}",
+                           last_delivery(hook)))
+        }
+        Some(ref hook) => {
+            (hook.id, format!("ok, last delivery: {}", last_delivery(hook)))
+        }
+    };
+    Ok((id, status))
+}
+
+fn config(app: &App,
+          user: &str,
+          repo: &str,
+          secret: &str) -> github::CreateWebhook {
+    github::CreateWebhook {
+        name: "web".to_string(),
+        active: true,
+        events: vec![
+            "issue_comment".to_string(),
+            "issues".to_string(),
+            "pull_request".to_string(),
+            "pull_request_review".to_string(),
+            "pull_request_review_comment".to_string(),
+            "repository".to_string(),
+            "status".to_string(),
+        ],
+        config: github::CreateWebhookConfig {
+            content_type: "json".to_string(),
+            url: url(app, user, repo),
+            secret: secret.to_string(),
+        },
+    }
+}
+
+/// Whether `hook` is active and delivers the events we want to the right
+/// place. GitHub never shows the secret, so it can't be compared.
+fn matches(hook: &github::Webhook, expected: &github::CreateWebhook) -> bool {
+    let config = |key: &str| hook.config.get(key).and_then(|v| v.as_string());
+    let mut events = hook.events.clone();
+    events.sort();
+    let mut expected_events = expected.events.clone();
+    expected_events.sort();
+
+    hook.active &&
+        events == expected_events &&
+        config("url") == Some(&expected.config.url[..]) &&
+        config("content_type") == Some(&expected.config.content_type[..])
+}
+
+fn last_delivery(hook: &github::Webhook) -> String {
+    match hook.last_response {
+        Some(github::WebhookResponse { code: Some(code), ref message, .. }) => {
+            match *message {
+                Some(ref message) => format!("{} {}", code, message),
+                None => code.to_string(),
+            }
+        }
+        Some(ref response) => response.status.clone(),
+        None => "unknown".to_string(),
+    }
+}
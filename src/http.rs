@@ -1,44 +1,151 @@
+//! Requests to the GitHub, Travis and AppVeyor APIs.
+//!
+//! Everything goes through the `ApiClient` in `App`, which is curl in
+//! production and a scripted fake in tests. Server errors are retried with a
+//! short backoff, while rate limits are reported straight away as
+//! `RateLimited` along with how long until they reset, leaving it to the
+//! caller whether that's worth waiting for.
+
 use std::str;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use curl::easy::{Easy, List};
-use oauth2::Token;
 use rustc_serialize::{json, Decodable, Encodable};
 
+use app::App;
 use errors::*;
+use github;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Patch,
+    Delete,
+}
+
+pub struct Response {
+    pub status: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Returns the value of the header `name`, which is case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref k, _)| k.to_lowercase() == name.to_lowercase())
+            .map(|&(_, ref v)| &v[..])
+    }
+}
+
+/// Something which can send HTTP requests.
+pub trait ApiClient: Send + Sync {
+    fn request(&self,
+               method: Method,
+               url: &str,
+               headers: &[String],
+               body: Option<&[u8]>) -> BorsResult<Response>;
+}
+
+/// The `ApiClient` used outside of tests.
+pub struct Curl;
 
-pub fn github_get<T>(url: &str, auth: &Token) -> BorsResult<T>
+/// How many times a request is retried after a server error.
+const MAX_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry, doubling on every further one.
+const RETRY_BACKOFF_MS: u64 = 250;
+
+pub fn github_get<T>(app: &App,
+                     url: &str,
+                     token: &str) -> BorsResult<T>
     where T: Decodable,
 {
     let headers = vec![
-        format!("Authorization: token {}", auth.access_token),
+        format!("Authorization: token {}", token),
         format!("Accept: application/vnd.github.v3+json"),
     ];
 
-    get(&format!("https://api.github.com{}", url), &headers)
+    let url = format!("{}{}", app.config.github_api_url, url);
+    get(&*app.http, &url, &headers)
 }
 
-pub fn github_post<T, U>(url: &str, auth: &Token, u: &U) -> BorsResult<T>
+/// Like `github_get`, but for endpoints which return a list in pages. Every
+/// page is fetched by following the `Link` headers.
+pub fn github_get_all<T>(app: &App,
+                         url: &str,
+                         token: &str) -> BorsResult<Vec<T>>
+    where T: Decodable,
+{
+    let headers = vec![
+        format!("Authorization: token {}", token),
+        format!("Accept: application/vnd.github.v3+json"),
+    ];
+
+    let mut url = format!("{}{}", app.config.github_api_url, url);
+    let mut all = Vec::new();
+    loop {
+        let resp = try!(send(&*app.http, Method::Get, &url, &headers, None));
+        let next = resp.header("Link").and_then(next_link);
+        let page: Vec<T> = try!(decode(&url, resp));
+        all.extend(page);
+        match next {
+            Some(next) => url = next,
+            None => return Ok(all),
+        }
+    }
+}
+
+pub fn github_post<T, U>(app: &App,
+                         url: &str,
+                         token: &str,
+                         u: &U) -> BorsResult<T>
     where T: Decodable,
           U: Encodable,
 {
     let headers = vec![
-        format!("Authorization: token {}", auth.access_token),
+        format!("Authorization: token {}", token),
         format!("Accept: application/vnd.github.v3+json"),
     ];
 
-    post(&format!("https://api.github.com{}", url), &headers, u)
+    let url = format!("{}{}", app.config.github_api_url, url);
+    post(&*app.http, &url, &headers, u)
 }
 
-pub fn github_delete(url: &str, auth: &Token) -> BorsResult<()> {
+pub fn github_patch<T, U>(app: &App,
+                          url: &str,
+                          token: &str,
+                          u: &U) -> BorsResult<T>
+    where T: Decodable,
+          U: Encodable,
+{
     let headers = vec![
-        format!("Authorization: token {}", auth.access_token),
+        format!("Authorization: token {}", token),
         format!("Accept: application/vnd.github.v3+json"),
     ];
 
-    delete(&format!("https://api.github.com{}", url), &headers)
+    let url = format!("{}{}", app.config.github_api_url, url);
+    patch(&*app.http, &url, &headers, u)
 }
 
-pub fn travis_get<T>(url: &str, token: &str) -> BorsResult<T>
+pub fn github_delete(app: &App,
+                     url: &str,
+                     token: &str) -> BorsResult<()> {
+    let headers = vec![
+        format!("Authorization: token {}", token),
+        format!("Accept: application/vnd.github.v3+json"),
+    ];
+
+    let url = format!("{}{}", app.config.github_api_url, url);
+    delete(&*app.http, &url, &headers)
+}
+
+pub fn travis_get<T>(app: &App,
+                     url: &str,
+                     token: &str) -> BorsResult<T>
     where T: Decodable,
 {
     let headers = vec![
@@ -46,10 +153,13 @@ pub fn travis_get<T>(url: &str, token: &str) -> BorsResult<T>
         format!("Accept: application/vnd.travis-ci.2+json"),
     ];
 
-    get(&format!("https://api.travis-ci.org{}", url), &headers)
+    let url = format!("{}{}", app.config.travis_api_url, url);
+    get(&*app.http, &url, &headers)
 }
 
-pub fn appveyor_get<T>(url: &str, token: &str) -> BorsResult<T>
+pub fn appveyor_get<T>(app: &App,
+                       url: &str,
+                       token: &str) -> BorsResult<T>
     where T: Decodable,
 {
     let headers = vec![
@@ -57,10 +167,14 @@ pub fn appveyor_get<T>(url: &str, token: &str) -> BorsResult<T>
         format!("Accept: application/json"),
     ];
 
-    get(&format!("https://ci.appveyor.com/api{}", url), &headers)
+    let url = format!("{}{}", app.config.appveyor_api_url, url);
+    get(&*app.http, &url, &headers)
 }
 
-pub fn appveyor_post<T, U>(url: &str, token: &str, u: &U) -> BorsResult<T>
+pub fn appveyor_post<T, U>(app: &App,
+                           url: &str,
+                           token: &str,
+                           u: &U) -> BorsResult<T>
     where T: Decodable,
           U: Encodable,
 {
@@ -70,92 +184,289 @@ pub fn appveyor_post<T, U>(url: &str, token: &str, u: &U) -> BorsResult<T>
         format!("Content-Type: application/json"),
     ];
 
-    post(&format!("https://ci.appveyor.com/api{}", url), &headers, u)
+    let url = format!("{}{}", app.config.appveyor_api_url, url);
+    post(&*app.http, &url, &headers, u)
+}
+
+/// Exchanges an OAuth `code` GitHub redirected back to us with for an access
+/// token.
+pub fn github_exchange(app: &App, code: &str) -> BorsResult<String> {
+    let headers = vec![
+        format!("Accept: application/json"),
+        format!("Content-Type: application/json"),
+    ];
+    let exchange = github::CreateAccessToken {
+        client_id: app.config.gh_client_id.clone(),
+        client_secret: app.config.gh_client_secret.clone(),
+        code: code.to_string(),
+    };
+    let url = format!("{}/login/oauth/access_token", app.config.github_url);
+    let token: github::AccessToken = try!(post(&*app.http, &url, &headers,
+                                               &exchange));
+    Ok(token.access_token)
 }
 
-pub fn get<T>(url: &str, headers: &[String]) -> BorsResult<T>
+pub fn get<T>(client: &ApiClient,
+              url: &str,
+              headers: &[String]) -> BorsResult<T>
     where T: Decodable,
 {
-    let mut handle = Easy::new();
-    let mut list = List::new();
-    try!(list.append("User-Agent: hello!"));
-    for header in headers {
-        try!(list.append(header));
-    }
+    let resp = try!(send(client, Method::Get, url, headers, None));
+    decode(url, resp)
+}
 
-    try!(handle.http_headers(list));
-    try!(handle.get(true));
-    try!(handle.url(url));
-    perform(&mut handle, url)
+pub fn post<T, U>(client: &ApiClient,
+                  url: &str,
+                  headers: &[String],
+                  u: &U) -> BorsResult<T>
+    where U: Encodable,
+          T: Decodable,
+{
+    let body = try!(json::encode(u));
+    let resp = try!(send(client, Method::Post, url, headers,
+                         Some(body.as_bytes())));
+    decode(url, resp)
 }
 
-pub fn post<T, U>(url: &str, headers: &[String], u: &U) -> BorsResult<T>
+pub fn patch<T, U>(client: &ApiClient,
+                   url: &str,
+                   headers: &[String],
+                   u: &U) -> BorsResult<T>
     where U: Encodable,
           T: Decodable,
 {
-    let mut handle = Easy::new();
-    let mut list = List::new();
-    try!(list.append("User-Agent: hello!"));
-    for header in headers {
-        try!(list.append(header));
-    }
+    let body = try!(json::encode(u));
+    let resp = try!(send(client, Method::Patch, url, headers,
+                         Some(body.as_bytes())));
+    decode(url, resp)
+}
 
-    try!(handle.http_headers(list));
-    try!(handle.post(true));
-    try!(handle.post_fields_copy(json::encode(u).unwrap().as_bytes()));
-    try!(handle.url(url));
-    perform(&mut handle, url)
+pub fn delete(client: &ApiClient,
+              url: &str,
+              headers: &[String]) -> BorsResult<()> {
+    let resp = try!(send(client, Method::Delete, url, headers, None));
+    decode(url, resp)
 }
 
-pub fn delete(url: &str, headers: &[String]) -> BorsResult<()> {
-    let mut handle = Easy::new();
-    let mut list = List::new();
-    try!(list.append("User-Agent: hello!"));
-    for header in headers {
-        try!(list.append(header));
+/// Sends a request, retrying server errors.
+///
+/// `POST`s aren't retried after a server error as they may have gone through
+/// anyway, e.g. posting a comment twice. Rate limits aren't waited out here as
+/// this may be running in a request handler or with a project locked.
+fn send(client: &ApiClient,
+        method: Method,
+        url: &str,
+        headers: &[String],
+        body: Option<&[u8]>) -> BorsResult<Response> {
+    let mut attempt = 0;
+    loop {
+        let resp = try!(client.request(method, url, headers, body));
+        if resp.status < 500 || method == Method::Post ||
+           attempt >= MAX_RETRIES {
+            return Ok(resp)
+        }
+        let wait = RETRY_BACKOFF_MS << attempt;
+        attempt += 1;
+        warn!("got a {} from {}, retrying in {}ms", resp.status, url, wait);
+        thread::sleep(Duration::from_millis(wait));
     }
+}
 
-    try!(handle.http_headers(list));
-    try!(handle.custom_request("DELETE"));
-    try!(handle.url(url));
-    perform(&mut handle, url)
-}
-
-fn perform<T: Decodable>(handle: &mut Easy, url: &str) -> BorsResult<T> {
-    let mut headers = Vec::new();
-    let mut data = Vec::new();
-
-    {
-        let mut t = handle.transfer();
-        try!(t.header_function(|data| {
-            headers.push(data.to_owned());
-            true
-        }));
-        try!(t.write_function(|buf| {
-            data.extend_from_slice(&buf);
-            Ok(buf.len())
-        }));
-
-        debug!("sending a request to {}", url);
-        try!(t.perform().chain_err(|| {
-            format!("failed to send http requests to {}", url)
-        }));
+/// How long until the rate limit which `resp` ran into resets, if any.
+fn rate_limit_wait(resp: &Response) -> Option<Duration> {
+    if resp.status != 403 && resp.status != 429 {
+        return None
+    }
+    let retry_after = resp.header("Retry-After")
+                          .and_then(|s| s.trim().parse().ok());
+    if let Some(secs) = retry_after {
+        return Some(Duration::from_secs(secs))
+    }
+    if resp.header("X-RateLimit-Remaining") != Some("0") {
+        return None
     }
+    let reset: u64 = match resp.header("X-RateLimit-Reset")
+                               .and_then(|s| s.trim().parse().ok()) {
+        Some(reset) => reset,
+        None => return None,
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                               .map(|d| d.as_secs())
+                               .unwrap_or(0);
+    Some(Duration::from_secs(reset.saturating_sub(now) + 1))
+}
 
-    match try!(handle.response_code()) {
+/// Returns the `rel="next"` url of a `Link` header.
+fn next_link(link: &str) -> Option<String> {
+    link.split(',').filter_map(|part| {
+        let mut pieces = part.split(';');
+        let url = pieces.next().unwrap().trim();
+        if pieces.any(|p| p.trim() == "rel=\"next\"") {
+            Some(url.trim_left_matches('<').trim_right_matches('>').to_string())
+        } else {
+            None
+        }
+    }).next()
+}
+
+fn decode<T: Decodable>(url: &str, resp: Response) -> BorsResult<T> {
+    let wait = rate_limit_wait(&resp);
+    let kind = match resp.status {
         200 |
         201 |
-        204 => {} // Ok!
+        204 => None, // Ok!
+        401 => Some(BorsErrorKind::Unauthorized),
+        404 => Some(BorsErrorKind::NotFound),
+        _ if wait.is_some() => {
+            Some(BorsErrorKind::RateLimited(wait.unwrap().as_secs()))
+        }
         code => {
-            return Err(format!("not a 200 code: {}\n\n{}\n", code,
-                               String::from_utf8_lossy(&data)).into())
+            let body = String::from_utf8_lossy(&resp.body).into_owned();
+            Some(BorsErrorKind::ApiError(code, body))
         }
+    };
+    if let Some(kind) = kind {
+        info!("got a {} from {}", resp.status, url);
+        return Err(kind.into())
     }
 
-    let json = try!(str::from_utf8(&data).chain_err(|| {
+    let json = try!(str::from_utf8(&resp.body).chain_err(|| {
         "github didn't send utf-8"
     }));
+    // e.g. a 204, decode it like a `null` so `()` can be requested
+    let json = if json.is_empty() {"null"} else {json};
     json::decode(json).chain_err(|| {
         "failed to parse json"
     })
 }
+
+impl ApiClient for Curl {
+    fn request(&self,
+               method: Method,
+               url: &str,
+               headers: &[String],
+               body: Option<&[u8]>) -> BorsResult<Response> {
+        let mut handle = Easy::new();
+        let mut list = List::new();
+        try!(list.append("User-Agent: hello!"));
+        for header in headers {
+            try!(list.append(header));
+        }
+        try!(handle.http_headers(list));
+
+        match method {
+            Method::Get => try!(handle.get(true)),
+            Method::Post => try!(handle.post(true)),
+            Method::Put => try!(handle.custom_request("PUT")),
+            Method::Patch => try!(handle.custom_request("PATCH")),
+            Method::Delete => try!(handle.custom_request("DELETE")),
+        }
+        if let Some(body) = body {
+            try!(handle.post_fields_copy(body));
+        }
+        try!(handle.url(url));
+
+        let mut headers = Vec::new();
+        let mut data = Vec::new();
+        {
+            let mut t = handle.transfer();
+            try!(t.header_function(|line| {
+                let line = String::from_utf8_lossy(line);
+                // A new status line, e.g. after a redirect
+                if line.starts_with("HTTP/") {
+                    headers.clear();
+                }
+                let mut parts = line.splitn(2, ':');
+                if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
+                    headers.push((k.trim().to_string(), v.trim().to_string()));
+                }
+                true
+            }));
+            try!(t.write_function(|buf| {
+                data.extend_from_slice(&buf);
+                Ok(buf.len())
+            }));
+
+            debug!("sending a request to {}", url);
+            try!(t.perform().chain_err(|| {
+                format!("failed to send http requests to {}", url)
+            }));
+        }
+
+        Ok(Response {
+            status: try!(handle.response_code()),
+            headers: headers,
+            body: data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::*;
+    use super::{decode, next_link, rate_limit_wait, Response};
+
+    fn response(status: u32, headers: &[(&str, &str)]) -> Response {
+        Response {
+            status: status,
+            headers: headers.iter().map(|&(k, v)| {
+                (k.to_string(), v.to_string())
+            }).collect(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn link_header() {
+        let link = "<https://api.github.com/repositories/1/collaborators?page=2>; \
+                    rel=\"next\", \
+                    <https://api.github.com/repositories/1/collaborators?page=5>; \
+                    rel=\"last\"";
+        assert_eq!(next_link(link).unwrap(),
+                   "https://api.github.com/repositories/1/collaborators?page=2");
+        let link = "<https://api.github.com/repositories/1/collaborators?page=1>; \
+                    rel=\"first\"";
+        assert_eq!(next_link(link), None);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |resp: Response| {
+            decode::<()>("http://api.github.invalid", resp).unwrap_err()
+        };
+        match *err(response(401, &[])).kind() {
+            BorsErrorKind::Unauthorized => {}
+            ref k => panic!("unexpected {:?}", k),
+        }
+        match *err(response(404, &[])).kind() {
+            BorsErrorKind::NotFound => {}
+            ref k => panic!("unexpected {:?}", k),
+        }
+        match *err(response(403, &[("X-RateLimit-Remaining", "0"),
+                                   ("X-RateLimit-Reset", "0")])).kind() {
+            BorsErrorKind::RateLimited(1) => {}
+            ref k => panic!("unexpected {:?}", k),
+        }
+        match *err(response(409, &[])).kind() {
+            BorsErrorKind::ApiError(409, _) => {}
+            ref k => panic!("unexpected {:?}", k),
+        }
+        decode::<()>("http://api.github.invalid", response(204, &[])).unwrap();
+    }
+
+    #[test]
+    fn rate_limits() {
+        assert_eq!(rate_limit_wait(&response(200, &[])), None);
+        assert_eq!(rate_limit_wait(&response(403, &[])), None);
+        assert_eq!(rate_limit_wait(&response(429, &[("Retry-After", "3")]))
+                       .unwrap().as_secs(), 3);
+        assert_eq!(rate_limit_wait(&response(403, &[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "0"),
+        ])).unwrap().as_secs(), 1);
+        assert_eq!(rate_limit_wait(&response(403, &[
+            ("X-RateLimit-Remaining", "10"),
+            ("X-RateLimit-Reset", "0"),
+        ])), None);
+    }
+}
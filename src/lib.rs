@@ -22,18 +22,21 @@ extern crate base64;
 extern crate rustc_serialize;
 extern crate url;
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::str;
 use std::sync::Arc;
 
-use conduit::{Request, Response, Handler};
+use conduit::{Handler, Method, Request, Response};
+use conduit_cookie::RequestSession;
 use conduit_middleware::MiddlewareBuilder;
 use conduit_router::{RouteBuilder, RequestParams};
 use rand::{Rng, thread_rng};
 use openssl::crypto::hmac;
 use openssl::crypto::hash::Type;
-use openssl::crypto::pkey::PKey;
 use rustc_serialize::hex::ToHex;
+use rustc_serialize::Encodable;
+use rustc_serialize::json::{self, Json, ToJson};
 
 use app::{App, RequestApp};
 use db::RequestTransaction;
@@ -49,6 +52,65 @@ pub struct Config {
     pub db_url: String,
     pub env: ::Env,
     pub host: String,
+    /// The Travis APIs whose keys webhook signatures are checked against.
+    /// Repositories may be on either travis-ci.org or travis-ci.com, so this
+    /// is a list, and it always includes `travis_api_url`.
+    pub travis_endpoints: Vec<String>,
+    pub encryption_key: String,
+    /// The previous `encryption_key` while it's being rotated.
+    pub old_encryption_key: Option<String>,
+    /// Where GitHub's web interface and OAuth endpoints live, e.g.
+    /// `https://github.com`.
+    pub github_url: String,
+    pub github_api_url: String,
+    /// The Travis API that tokens are checked and builds are looked up with.
+    pub travis_api_url: String,
+    pub appveyor_api_url: String,
+}
+
+impl Config {
+    /// Reads the configuration shared by the web server and the worker from
+    /// the environment, panicking if anything required is missing.
+    pub fn from_env() -> Config {
+        let heroku = std::env::var("HEROKU").is_ok();
+        let host = if heroku {
+            format!("https://bors2-test.herokuapp.com")
+        } else {
+            format!("http://localhost:3000")
+        };
+        let bors_env = if heroku {
+            Env::Production
+        } else {
+            Env::Development
+        };
+        let travis_api_url = env_or("TRAVIS_API_URL",
+                                    "https://api.travis-ci.org");
+        let mut travis_endpoints = env_or("TRAVIS_ENDPOINTS",
+                                          "https://api.travis-ci.org,\
+                                           https://api.travis-ci.com")
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .collect::<Vec<_>>();
+        if !travis_endpoints.contains(&travis_api_url) {
+            travis_endpoints.push(travis_api_url.clone());
+        }
+        Config {
+            session_key: env("SESSION_KEY"),
+            gh_client_id: env("GH_CLIENT_ID"),
+            gh_client_secret: env("GH_CLIENT_SECRET"),
+            db_url: env("DATABASE_URL"),
+            env: bors_env,
+            host: host,
+            travis_endpoints: travis_endpoints,
+            encryption_key: env("ENCRYPTION_KEY"),
+            old_encryption_key: std::env::var("OLD_ENCRYPTION_KEY").ok(),
+            github_url: env_or("GITHUB_URL", "https://github.com"),
+            github_api_url: env_or("GITHUB_API_URL", "https://api.github.com"),
+            travis_api_url: travis_api_url,
+            appveyor_api_url: env_or("APPVEYOR_API_URL",
+                                     "https://ci.appveyor.com/api"),
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -59,14 +121,19 @@ pub enum Env {
 }
 
 pub mod app;
+pub mod commands;
+pub mod crypto;
 pub mod db;
 pub mod errors;
 pub mod github;
+pub mod hooks;
 pub mod http;
 pub mod models;
+pub mod queue;
 pub mod travis;
 pub mod appveyor;
 pub mod util;
+pub mod worker;
 
 pub fn env(s: &str) -> String {
     match std::env::var(s) {
@@ -75,6 +142,15 @@ pub fn env(s: &str) -> String {
     }
 }
 
+/// Like `env`, but falls back to `default` if `s` isn't defined.
+pub fn env_or(s: &str, default: &str) -> String {
+    std::env::var(s).unwrap_or(default.to_string())
+}
+
+/// What the OAuth `state` is for when a user logs in, as opposed to the
+/// `user/repo` being added.
+const LOGIN_TARGET: &'static str = "login";
+
 pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     let mut router = RouteBuilder::new();
 
@@ -83,6 +159,15 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     router.get("/repos/:user/:repo", C(repo_show));
     router.post("/repos/:user/:repo/add-travis-token", C(repo_add_travis));
     router.post("/repos/:user/:repo/add-appveyor-token", C(repo_add_appveyor));
+    router.post("/repos/:user/:repo/rollup", C(repo_rollup));
+    router.post("/repos/:user/:repo/reviewers", C(repo_set_reviewer));
+    router.post("/repos/:user/:repo/sync-reviewers", C(repo_sync_reviewers));
+    router.post("/repos/:user/:repo/check-webhook", C(repo_check_webhook));
+    router.post("/repos/:user/:repo/delete", C(repo_delete));
+    router.get("/repos/:user/:repo/events", C(repo_events));
+    router.post("/repos/:user/:repo/events/:id/reset", C(repo_reset_event));
+    router.get("/login", C(login));
+    router.post("/logout", C(logout));
     router.get("/authorize/github", C(authorize_github));
     router.post("/webhook/github/:user/:repo", C(github_webhook));
     router.post("/webhook/appveyor/:user/:repo", C(appveyor_webhook));
@@ -147,8 +232,20 @@ fn repo_new(req: &mut Request) -> BorsResult<Response> {
     let repo = query.find(|&(ref a, _)| a == "repo")
                     .map(|(_, value)| value)
                     .expect("failed to find `repo` in query string");
-    let app = req.app();
-    let redirect_url = app.github.authorize_url(repo.into_owned());
+    {
+        let mut parts = repo.splitn(2, '/');
+        let user = parts.next().unwrap();
+        let name = match parts.next() {
+            Some(name) => name,
+            None => {
+                req.set_flash_error("repository should look like `user/repo`");
+                return repos(req)
+            }
+        };
+        try!(require_push_access(req, user, name));
+    }
+    let state = oauth_state(req, &repo);
+    let redirect_url = req.app().github.authorize_url(state);
     debug!("oauth redirect to {}", redirect_url);
     Ok(util::redirect(&redirect_url.to_string()))
 }
@@ -164,66 +261,201 @@ fn authorize_github(req: &mut Request) -> BorsResult<Response> {
                      .find(|&&(ref a, _)| a == "state")
                      .map(|&(_, ref value)| &value[..])
                      .expect("state not present in url");
-    try!(add_project(req, &code, &state).chain_err(|| {
+    let target = try!(check_oauth_state(req, state));
+    if target == LOGIN_TARGET {
+        try!(login_user(req, code).chain_err(|| "failed to log in"));
+        return Ok(util::redirect("/"))
+    }
+
+    // Check before adding so a lack of access isn't lost in the error chain
+    {
+        let mut parts = target.splitn(2, '/');
+        let user = parts.next().unwrap();
+        let name = parts.next().unwrap_or("");
+        try!(require_push_access(req, user, name));
+    }
+    try!(add_project(req, &code, &target).chain_err(|| {
         "failed to add project"
     }));
     Ok(util::redirect("/"))
 }
 
+fn login(req: &mut Request) -> BorsResult<Response> {
+    let state = oauth_state(req, LOGIN_TARGET);
+    let redirect_url = req.app().github.authorize_url(state);
+    Ok(util::redirect(&redirect_url.to_string()))
+}
+
+/// Creates the OAuth `state` for sending the user to GitHub to authorize
+/// `target`, tied to this session by a random nonce.
+fn oauth_state(req: &mut Request, target: &str) -> String {
+    let nonce = thread_rng().gen_ascii_chars().take(20).collect::<String>();
+    req.session().insert("github_oauth_state".to_string(), nonce.clone());
+    format!("{}:{}", nonce, target)
+}
+
+/// Checks the OAuth `state` GitHub sent back against the session, returning
+/// what it was for.
+fn check_oauth_state(req: &mut Request, state: &str) -> BorsResult<String> {
+    let expected = req.session().remove("github_oauth_state");
+    let mut parts = state.splitn(2, ':');
+    let nonce = parts.next().unwrap();
+    match (expected, parts.next()) {
+        (Some(ref expected), Some(target)) if expected == nonce => {
+            Ok(target.to_string())
+        }
+        _ => {
+            let msg = "oauth state didn't match the session".to_string();
+            Err(BorsErrorKind::BadRequest(msg).into())
+        }
+    }
+}
+
+/// Returns the token which forms must send back, see `check_csrf_token`.
+fn csrf_token(req: &mut Request) -> String {
+    let existing = req.session().get("csrf_token").cloned();
+    match existing {
+        Some(token) => token,
+        None => {
+            let token = thread_rng().gen_ascii_chars().take(32)
+                                    .collect::<String>();
+            req.session().insert("csrf_token".to_string(), token.clone());
+            token
+        }
+    }
+}
+
+/// Makes sure a `POST` came from one of our own forms, which send the
+/// session's token in the query string.
+fn check_csrf_token(req: &mut Request) -> BorsResult<()> {
+    let query = req.query_string().unwrap_or("").to_string();
+    let given = url::form_urlencoded::parse(query.as_bytes())
+                    .find(|&(ref a, _)| a == "csrf_token")
+                    .map(|(_, value)| value.into_owned());
+    let expected = req.session().get("csrf_token").cloned();
+    match (given, expected) {
+        (Some(ref given), Some(ref expected))
+            if given.len() == expected.len() &&
+               openssl::crypto::memcmp::eq(given.as_bytes(),
+                                           expected.as_bytes()) => Ok(()),
+        _ => {
+            let msg = "missing or invalid csrf token".to_string();
+            Err(BorsErrorKind::BadRequest(msg).into())
+        }
+    }
+}
+
+fn logout(req: &mut Request) -> BorsResult<Response> {
+    req.session().remove("user_id");
+    req.session().remove("github_login");
+    req.session().remove("csrf_token");
+    Ok(util::redirect("/"))
+}
+
+/// Finishes logging in a user once GitHub has redirected back to us.
+fn login_user(req: &mut Request, code: &str) -> BorsResult<()> {
+    let token = try!(http::github_exchange(req.app(), code));
+    let github_user: github::User = try!(http::github_get(
+        req.app(),
+        "/user",
+        &token));
+    let user = try!(User::upsert(try!(req.tx()),
+                                 &req.app().encryption_key,
+                                 github_user.id,
+                                 &github_user.login,
+                                 &token));
+    req.session().insert("user_id".to_string(), user.id.to_string());
+    req.session().insert("github_login".to_string(), user.login);
+    // Forms rendered before logging in shouldn't work afterwards.
+    req.session().remove("csrf_token");
+    Ok(())
+}
+
+/// Returns the user logged in to this session.
+fn req_user(req: &mut Request) -> BorsResult<User> {
+    let id = req.session().get("user_id").and_then(|id| id.parse().ok());
+    match id {
+        Some(id) => {
+            User::find(try!(req.tx()), &req.app().encryption_key, id)
+        }
+        None => Err(BorsErrorKind::NotLoggedIn.into()),
+    }
+}
+
+/// Checks with GitHub that the logged in user can push to `user/repo`.
+///
+/// Every route which changes a project's configuration must go through this
+/// first.
+fn require_push_access(req: &mut Request,
+                       user: &str,
+                       repo: &str) -> BorsResult<User> {
+    let current = try!(req_user(req));
+    let url = format!("/repos/{}/{}", user, repo);
+    let res = http::github_get::<github::Repository>(
+        req.app(),
+        &url,
+        &current.github_access_token);
+    let repo = match res {
+        Ok(repo) => repo,
+        Err(e) => {
+            // GitHub hides private repositories from those who can't see
+            // them, and a revoked token means logging in again.
+            match *e.kind() {
+                BorsErrorKind::NotFound => {
+                    return Err(BorsErrorKind::Forbidden.into())
+                }
+                BorsErrorKind::Unauthorized => {
+                    return Err(BorsErrorKind::NotLoggedIn.into())
+                }
+                _ => {}
+            }
+            return Err(e)
+        }
+    };
+    match repo.permissions {
+        Some(ref p) if p.admin || p.push => Ok(current),
+        _ => Err(BorsErrorKind::Forbidden.into()),
+    }
+}
+
+/// Like `req_project`, but also requires push access to the project.
+fn req_project_for_update(req: &mut Request) -> BorsResult<Project> {
+    let project = try!(req_project(req));
+    try!(require_push_access(req, &project.repo_user, &project.repo_name));
+    Ok(project)
+}
+
 fn add_project(req: &mut Request, code: &str, repo_name: &str) -> BorsResult<()> {
-    let github_access_token = try!(req.app().github.exchange(code.to_string()));
+    let github_access_token = try!(http::github_exchange(req.app(), code));
 
     let url = format!("/repos/{}", repo_name);
-    let repo: github::Repository = try!(http::github_get(&url,
-                                                         &github_access_token));
+    let repo: github::Repository = try!(http::github_get(
+        req.app(),
+        &url,
+        &github_access_token));
 
     let mut parts = repo_name.splitn(2, '/');
     let user = parts.next().unwrap();
     let name = parts.next().unwrap();
-    let github_webhook_secret = thread_rng().gen_ascii_chars().take(20)
-                                            .collect::<String>();
-
-    try!(add_github_webhook_to_bors2(req.app(),
-                                     &github_access_token,
-                                     user,
-                                     name,
-                                     &github_webhook_secret));
-
-    try!(Project::insert(try!(req.tx()),
-                         user,
-                         name,
-                         repo.id,
-                         &github_access_token.access_token,
-                         &github_webhook_secret));
-    Ok(())
-}
 
-fn add_github_webhook_to_bors2(app: &App,
-                               token: &oauth2::Token,
-                               user: &str,
-                               repo: &str,
-                               secret: &str) -> BorsResult<()> {
-    let url = format!("/repos/{}/{}/hooks", user, repo);
-    let webhook = github::CreateWebhook {
-        name: "web".to_string(),
-        active: true,
-        events: vec![
-            "issue_comment".to_string(),
-            "issues".to_string(),
-            "pull_request".to_string(),
-            "pull_request_review".to_string(),
-            "pull_request_review_comment".to_string(),
-            "status".to_string(),
-        ],
-        config: github::CreateWebhookConfig {
-            content_type: "json".to_string(),
-            url: format!("{}/webhook/github/{}/{}", app.config.host, user, repo),
-            secret: secret.to_string(),
-        },
+    // Adding a repository again, e.g. to replace a revoked token, updates its
+    // project and keeps the secret so the existing webhook can stay.
+    let tx = try!(req.tx());
+    let key = &req.app().encryption_key;
+    let existing = try!(Project::find_by_github_id(tx, key, repo.id));
+    let github_webhook_secret = match existing {
+        Some(project) => project.github_webhook_secret,
+        None => thread_rng().gen_ascii_chars().take(20).collect::<String>(),
     };
-    let w: github::Webhook = try!(http::github_post(&url, &token, &webhook));
-    drop(w);
-    Ok(())
+
+    let project = try!(Project::upsert(tx,
+                                       key,
+                                       user,
+                                       name,
+                                       repo.id,
+                                       &github_access_token,
+                                       &github_webhook_secret));
+    hooks::check(req.app(), tx, &project)
 }
 
 fn repo_add_travis(req: &mut Request) -> BorsResult<Response> {
@@ -234,24 +466,41 @@ fn repo_add_travis(req: &mut Request) -> BorsResult<Response> {
     let token = query.iter().find(|q| q.0 == "token").unwrap();
     let token = &token.1;
 
-    let project = try!(req_project(req));
+    let project = try!(req_project_for_update(req));
 
     let url = format!("/repos/{}/{}", project.repo_user, project.repo_name);
-    let travis_repo: travis::GetRepository = match http::travis_get(&url, &token) {
+    let res = http::travis_get(req.app(), &url, &token);
+    let travis_repo: travis::GetRepository = match res {
         Ok(repo) => repo,
-        Err(_) => {
-            req.set_flash_error("travis token was invalid");
+        Err(e) => {
+            // Travis answers a bad token with a 403 "access denied"
+            let msg = match *e.kind() {
+                BorsErrorKind::Unauthorized |
+                BorsErrorKind::ApiError(403, _) => {
+                    Some("travis token was invalid")
+                }
+                BorsErrorKind::NotFound => Some("repository not found on travis"),
+                _ => None,
+            };
+            match msg {
+                Some(msg) => req.set_flash_error(msg),
+                None => return Err(e),
+            }
             return repo_show(req);
         }
     };
 
     let url = format!("/repos/{}/settings", travis_repo.repo.id);
-    if http::travis_get::<travis::GetRepoSettings>(&url, &token).is_err() {
+    let res = http::travis_get::<travis::GetRepoSettings>(req.app(), &url,
+                                                          &token);
+    if res.is_err() {
         req.set_flash_error("project not registered?");
         return repo_show(req);
     }
 
-    try!(project.set_travis_token(try!(req.tx()), &token));
+    try!(project.set_travis_token(try!(req.tx()),
+                                  &req.app().encryption_key,
+                                  &token));
 
     Ok(util::redirect(&format!("/repos/{}/{}",
                                project.repo_user,
@@ -265,15 +514,19 @@ fn repo_add_appveyor(req: &mut Request) -> BorsResult<Response> {
 
     let token = query.iter().find(|q| q.0 == "token").unwrap();
     let token = &token.1;
-    let project = try!(req_project(req));
+    let project = try!(req_project_for_update(req));
 
     // Test out the token by fetching the user's list of projects
     let url = format!("/projects");
-    let projects: Vec<appveyor::Project> = match http::appveyor_get(&url, &token) {
+    let res = http::appveyor_get(req.app(), &url, &token);
+    let projects: Vec<appveyor::Project> = match res {
         Ok(projects) => projects,
-        Err(_) => {
-            req.set_flash_error("appveyor token was invalid");
-            return repo_show(req)
+        Err(e) => {
+            if let BorsErrorKind::Unauthorized = *e.kind() {
+                req.set_flash_error("appveyor token was invalid");
+                return repo_show(req)
+            }
+            return Err(e)
         }
     };
     let repo_name = format!("{}/{}", project.repo_user, project.repo_name);
@@ -287,122 +540,469 @@ fn repo_add_appveyor(req: &mut Request) -> BorsResult<Response> {
             repositoryProvider: "gitHub".to_string(),
             repositoryName: repo_name,
         };
-        let project: appveyor::Project = try!(http::appveyor_post("/projects",
-                                                                  &token,
-                                                                  &new));
+        let project: appveyor::Project = try!(http::appveyor_post(
+            req.app(),
+            "/projects",
+            &token,
+            &new));
         drop(project);
     }
 
     // Ok, set the token and go back to the repo
-    try!(project.set_appveyor_token(try!(req.tx()), &token));
+    try!(project.set_appveyor_token(try!(req.tx()),
+                                    &req.app().encryption_key,
+                                    &token));
     Ok(util::redirect(&format!("/repos/{}/{}",
                                project.repo_user,
                                project.repo_name)))
 }
 
+fn repo_rollup(req: &mut Request) -> BorsResult<Response> {
+    let project = try!(req_project_for_update(req));
+    let rollup = try!(queue::rollup(req.app(), try!(req.tx()), &project));
+    match rollup {
+        Some(pr) => {
+            Ok(util::redirect(&format!("{}/{}/{}/pull/{}",
+                                       req.app().config.github_url,
+                                       project.repo_user,
+                                       project.repo_name,
+                                       pr.number)))
+        }
+        None => {
+            req.set_flash_error("no pull requests could be rolled up");
+            repo_show(req)
+        }
+    }
+}
+
+/// Checks on the project's webhook right away instead of waiting for the
+/// worker to do so, repairing it if need be.
+fn repo_check_webhook(req: &mut Request) -> BorsResult<Response> {
+    let project = try!(req_project_for_update(req));
+    let res = hooks::check(req.app(), try!(req.tx()), &project);
+    if let Err(e) = res {
+        // The error is stored as the webhook's status, which is shown too.
+        req.set_flash_error(&format!("failed to check the webhook: {}", e));
+        return repo_show(req)
+    }
+    Ok(util::redirect(&format!("/repos/{}/{}",
+                               project.repo_user,
+                               project.repo_name)))
+}
+
+/// Removes a project from bors2, along with the webhook it created on GitHub.
+fn repo_delete(req: &mut Request) -> BorsResult<Response> {
+    let project = try!(req_project_for_update(req));
+    let webhook_id = match project.github_webhook_id {
+        Some(id) => Some(id),
+        None => try!(hooks::find(req.app(), &project)).map(|h| h.id),
+    };
+    if let Some(id) = webhook_id {
+        let url = format!("/repos/{}/{}/hooks/{}", project.repo_user,
+                          project.repo_name, id);
+        let res = http::github_delete(req.app(), &url,
+                                      &project.github_access_token);
+        if let Err(e) = res {
+            // The hook may have been removed by hand already, and a revoked
+            // token shouldn't keep the project around forever.
+            let ignore = match *e.kind() {
+                BorsErrorKind::NotFound |
+                BorsErrorKind::Unauthorized => true,
+                _ => false,
+            };
+            if !ignore {
+                return Err(e)
+            }
+            warn!("failed to delete webhook of {}/{}: {}", project.repo_user,
+                  project.repo_name, e);
+        }
+    }
+
+    try!(project.delete(try!(req.tx())));
+    Ok(util::redirect("/"))
+}
+
+#[derive(RustcEncodable)]
+struct ReposView {
+    projects: Vec<ProjectView>,
+}
+
+#[derive(RustcEncodable)]
+struct ProjectView {
+    repo_user: String,
+    repo_name: String,
+}
+
 fn repos(req: &mut Request) -> BorsResult<Response> {
     let tx = try!(req.tx());
-    let projects = try!(Project::all(tx));
-    let mut page = format!(r#"
-<form action="/repos" method=post>
-Add repo: <input name=repo type=text />
-</form>
+    let projects = try!(Project::all(tx, &req.app().encryption_key));
+    let view = ReposView {
+        projects: projects.into_iter().map(|project| {
+            ProjectView {
+                repo_user: project.repo_user,
+                repo_name: project.repo_name,
+            }
+        }).collect(),
+    };
+    render(req, "repos", &view)
+}
+
+#[derive(RustcEncodable)]
+struct RepoView {
+    github_url: String,
+    repo_user: String,
+    repo_name: String,
+    needs_travis_token: bool,
+    needs_appveyor_token: bool,
+    webhook_status: Option<String>,
+    queue: Vec<QueueRowView>,
+    reviewers: Vec<ReviewerView>,
+}
+
+#[derive(RustcEncodable)]
+struct EventsView {
+    repo_user: String,
+    repo_name: String,
+    provider: String,
+    event: String,
+    state: String,
+    events: Vec<EventView>,
+}
+
+#[derive(RustcEncodable)]
+struct EventView {
+    id: i32,
+    provider: &'static str,
+    provider_event_id: String,
+    provider_event: String,
+    state: &'static str,
+    event: String,
+}
+
+#[derive(RustcEncodable)]
+struct ReviewerView {
+    login: String,
+    permission: &'static str,
+}
+
+#[derive(RustcEncodable)]
+struct QueueRowView {
+    url: String,
+    number: i32,
+    status: &'static str,
+    title: String,
+    approved_by: String,
+    priority: i32,
+    rollup: &'static str,
+    mergeable: &'static str,
+    try_status: &'static str,
+}
+
+fn repo_set_reviewer(req: &mut Request) -> BorsResult<Response> {
+    let mut query = Vec::new();
+    try!(req.body().read_to_end(&mut query));
+    let query = url::form_urlencoded::parse(&query).collect::<Vec<_>>();
+
+    let login = query.iter().find(|q| q.0 == "login").unwrap();
+    let login = login.1.trim().trim_left_matches('@');
+    let permission = query.iter().find(|q| q.0 == "permission").unwrap();
+    let project = try!(req_project_for_update(req));
+
+    if login.is_empty() {
+        req.set_flash_error("reviewer login can't be empty");
+        return repo_show(req)
+    }
+    let tx = try!(req.tx());
+    match &permission.1[..] {
+        "review" => {
+            try!(Reviewer::set(tx, project.id, login, Permission::Review));
+        }
+        "try" => {
+            try!(Reviewer::set(tx, project.id, login, Permission::Try));
+        }
+        _ => try!(Reviewer::remove(tx, project.id, login)),
+    }
+    Ok(util::redirect(&format!("/repos/{}/{}",
+                               project.repo_user,
+                               project.repo_name)))
+}
 
-<table>
-"#);
+/// Makes reviewers out of everyone with push access to the repository, or
+/// out of the members of a GitHub team if one is given as `org/team`.
+///
+/// Existing reviewers are left alone, so people can still be added by hand.
+fn repo_sync_reviewers(req: &mut Request) -> BorsResult<Response> {
+    let mut query = Vec::new();
+    try!(req.body().read_to_end(&mut query));
+    let query = url::form_urlencoded::parse(&query).collect::<Vec<_>>();
+
+    let team = query.iter().find(|q| q.0 == "team")
+                    .map(|q| q.1.trim())
+                    .unwrap_or("");
+    let project = try!(req_project_for_update(req));
+    let token = &project.github_access_token;
+
+    let logins = if team.is_empty() {
+        let url = format!("/repos/{}/{}/collaborators", project.repo_user,
+                          project.repo_name);
+        let collaborators: Vec<github::Collaborator> =
+            try!(http::github_get_all(req.app(), &url, token));
+        collaborators.into_iter().filter(|c| {
+            c.permissions.as_ref().map(|p| p.admin || p.push).unwrap_or(false)
+        }).map(|c| c.login).collect::<Vec<_>>()
+    } else {
+        let mut parts = team.splitn(2, '/');
+        let org = parts.next().unwrap();
+        let slug = parts.next().unwrap_or("");
+        let url = format!("/orgs/{}/teams", org);
+        let teams: Vec<github::Team> = try!(http::github_get_all(req.app(),
+                                                                 &url,
+                                                                 token));
+        let team = match teams.into_iter().find(|t| t.slug == slug) {
+            Some(team) => team,
+            None => {
+                req.set_flash_error("team not found, expected `org/team`");
+                return repo_show(req)
+            }
+        };
+        let url = format!("/teams/{}/members", team.id);
+        let members: Vec<github::User> = try!(http::github_get_all(req.app(),
+                                                                   &url,
+                                                                   token));
+        members.into_iter().map(|u| u.login).collect()
+    };
 
-    for project in projects {
-        page.push_str(&format!("<tr>\
-            <td>\
-                <a href='/repos/{user}/{name}'>{user}/{name}</a>
-            </td>\
-        </tr>\n",
-        user = project.repo_user,
-        name = project.repo_name,
-        ));
+    let tx = try!(req.tx());
+    for login in logins {
+        try!(Reviewer::set(tx, project.id, &login, Permission::Review));
     }
+    Ok(util::redirect(&format!("/repos/{}/{}",
+                               project.repo_user,
+                               project.repo_name)))
+}
 
-    page.push_str("\n</table>");
+/// Lists the events stored for a project, so they can be inspected and
+/// handed back to the worker if it mishandled them.
+fn repo_events(req: &mut Request) -> BorsResult<Response> {
+    let query = req.query_string().unwrap_or("").to_string();
+    let query = url::form_urlencoded::parse(query.as_bytes()).collect::<Vec<_>>();
+    let param = |name: &str| {
+        query.iter()
+             .find(|&&(ref a, ref value)| a == name && !value.is_empty())
+             .map(|&(_, ref value)| &value[..])
+    };
+    let provider = match param("provider") {
+        Some("github") => Some(Provider::GitHub),
+        Some("travis") => Some(Provider::Travis),
+        Some("appveyor") => Some(Provider::AppVeyor),
+        _ => None,
+    };
+    let state = match param("state") {
+        Some("unprocessed") => Some(State::Unprocessed),
+        Some("processed") => Some(State::Processed),
+        Some("failed") => Some(State::Failed),
+        _ => None,
+    };
+    let event = param("event");
+
+    // Raw payloads can contain things we'd rather not show everyone
+    let project = try!(req_project_for_update(req));
+    let events = try!(Event::recent(try!(req.tx()), project.id, provider,
+                                    event, state, 100));
+
+    let view = EventsView {
+        provider: param("provider").unwrap_or("").to_string(),
+        event: event.unwrap_or("").to_string(),
+        state: param("state").unwrap_or("").to_string(),
+        events: events.into_iter().map(|e| {
+            EventView {
+                id: e.id,
+                provider: match e.provider_id {
+                    Provider::GitHub => "github",
+                    Provider::Travis => "travis",
+                    Provider::AppVeyor => "appveyor",
+                },
+                provider_event_id: e.provider_event_id,
+                provider_event: e.provider_event,
+                state: match e.state {
+                    State::Unprocessed => "unprocessed",
+                    State::Processed => "processed",
+                    State::Failed => "failed",
+                },
+                event: e.event,
+            }
+        }).collect(),
+        repo_user: project.repo_user,
+        repo_name: project.repo_name,
+    };
+    render(req, "events", &view)
+}
 
-    Ok(site_html(req, &page))
+/// Marks an event as unprocessed so the worker handles it again.
+fn repo_reset_event(req: &mut Request) -> BorsResult<Response> {
+    let project = try!(req_project_for_update(req));
+    let id = req.params()["id"].parse::<i32>().ok();
+    let found = {
+        let tx = try!(req.tx());
+        let event = match id {
+            Some(id) => try!(Event::find(tx, id)),
+            None => None,
+        };
+        match event {
+            Some(ref event) if event.project_id == Some(project.id) => {
+                try!(event.set_state(tx, State::Unprocessed));
+                true
+            }
+            _ => false,
+        }
+    };
+    if !found {
+        req.set_flash_error("event not found");
+        return repo_events(req)
+    }
+    Ok(util::redirect(&format!("/repos/{}/{}/events",
+                               project.repo_user,
+                               project.repo_name)))
 }
 
 fn repo_show(req: &mut Request) -> BorsResult<Response> {
     let project = try!(req_project(req));
-
-    let mut page = format!("\
-<h2>\
-    <a href='https://github.com/{repo_user}/{repo_name}'>\
-        {repo_user}/{repo_name}\
-    </a>\
-</h2>
-",
-        repo_name = project.repo_name,
-        repo_user = project.repo_user);
-
-    if project.travis_access_token.is_none() {
-        page.push_str(&format!("\
-            <form action='/repos/{repo_user}/{repo_name}/add-travis-token' \
-                  method=post>
-                <input type=text name=token placeholder='Enter travis token'/>
-            </form>
-        ",
-        repo_user = project.repo_user,
-        repo_name = project.repo_name));
-    }
-
-    if project.appveyor_token.is_none() {
-        page.push_str(&format!("\
-            <form action='/repos/{repo_user}/{repo_name}/add-appveyor-token' \
-                  method=post>
-                <input type=text name=token placeholder='Enter appveyor token'/>
-            </form>
-        ",
-        repo_user = project.repo_user,
-        repo_name = project.repo_name));
-    }
-
-    Ok(site_html(req, &page))
+    let github_url = req.app().config.github_url.clone();
+
+    let prs = try!(PullRequest::queue(try!(req.tx()), project.id));
+    let queue = prs.into_iter().map(|pr| {
+        let status = match pr.status {
+            Status::Idle if pr.rollup_id.is_some() => "rolled up",
+            Status::Idle if pr.approved_by.is_some() => "approved",
+            Status::Idle => "",
+            Status::Pending => "pending",
+            Status::Success => "success",
+            Status::Failure => "failure",
+            Status::Error => "error",
+        };
+        let try_status = match pr.try_status {
+            Status::Idle if pr.try_requested => "requested",
+            Status::Idle => "",
+            Status::Pending => "pending",
+            Status::Success => "success",
+            Status::Failure => "failure",
+            Status::Error => "error",
+        };
+        QueueRowView {
+            url: format!("{}/{}/{}/pull/{}", github_url,
+                         project.repo_user, project.repo_name, pr.number),
+            number: pr.number,
+            status: status,
+            title: pr.title,
+            approved_by: pr.approved_by.unwrap_or(String::new()),
+            priority: pr.priority,
+            rollup: if pr.rollup {"yes"} else {""},
+            mergeable: match pr.mergeable {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "",
+            },
+            try_status: try_status,
+        }
+    }).collect();
+
+    let reviewers = try!(Reviewer::all(try!(req.tx()), project.id));
+    let reviewers = reviewers.into_iter().map(|r| {
+        ReviewerView {
+            login: r.login,
+            permission: match r.permission {
+                Permission::Review => "review",
+                Permission::Try => "try",
+            },
+        }
+    }).collect();
+
+    let view = RepoView {
+        github_url: github_url,
+        needs_travis_token: project.travis_access_token.is_none(),
+        needs_appveyor_token: project.appveyor_token.is_none(),
+        webhook_status: project.github_webhook_status,
+        repo_user: project.repo_user,
+        repo_name: project.repo_name,
+        queue: queue,
+        reviewers: reviewers,
+    };
+    render(req, "repo", &view)
 }
 
 fn req_project(req: &Request) -> BorsResult<Project> {
     let user = &req.params()["user"];
     let repo = &req.params()["repo"];
-    Project::find_by_name(try!(req.tx()), user, repo)
+    Project::find_by_name(try!(req.tx()),
+                          &req.app().encryption_key,
+                          user,
+                          repo)
+}
+
+/// Returns the first value of the header `name`, or a 400 error if the
+/// request doesn't have it.
+fn header(req: &Request, name: &str) -> BorsResult<String> {
+    match req.headers().find(name) {
+        Some(ref values) if !values.is_empty() => Ok(values[0].to_string()),
+        _ => Err(BorsErrorKind::BadRequest(format!("missing `{}` header",
+                                                   name)).into()),
+    }
 }
 
 fn github_webhook(req: &mut Request) -> BorsResult<Response> {
-    let event = req.headers().find("X-GitHub-Event")
-                   .expect("event not present")[0].to_string();
-    let signature = req.headers().find("X-Hub-Signature")
-                       .expect("signature not present")[0].to_string();
-    let id = req.headers().find("X-GitHub-Delivery")
-                .expect("delivery not present")[0].to_string();
+    let event = try!(header(req, "X-GitHub-Event"));
+    let id = try!(header(req, "X-GitHub-Delivery"));
+    // Prefer the SHA-256 signature, GitHub only keeps sending the SHA-1 one
+    // for compatibility.
+    let (ty, prefix, signature) = match header(req, "X-Hub-Signature-256") {
+        Ok(signature) => (Type::SHA256, "sha256=", signature),
+        Err(_) => match header(req, "X-Hub-Signature") {
+            Ok(signature) => (Type::SHA1, "sha1=", signature),
+            Err(_) => return Err(BorsErrorKind::InvalidSignature.into()),
+        },
+    };
 
     let mut body = Vec::new();
     try!(req.body().read_to_end(&mut body));
 
+    let payload = try!(str::from_utf8(&body));
+
+    // After a rename GitHub keeps delivering to the url with the old name, so
+    // prefer finding the project by the repository's id.
     let tx = try!(req.tx());
-    let project = try!(req_project(req));
+    let repo_id = Json::from_str(payload).ok().and_then(|json| {
+        json.find_path(&["repository", "id"]).and_then(|id| id.as_i64())
+    });
+    let found = match repo_id {
+        Some(id) => try!(Project::find_by_github_id(tx,
+                                                    &req.app().encryption_key,
+                                                    id as i32)),
+        None => None,
+    };
+    let project = match found {
+        Some(project) => project,
+        None => try!(req_project(req)),
+    };
 
-    let my_signature = try!(hmac::hmac(Type::SHA1,
+    let my_signature = try!(hmac::hmac(ty,
                                        project.github_webhook_secret.as_bytes(),
                                        &body));
-    let my_signature = format!("sha1={}", my_signature.to_hex());
-    if !openssl::crypto::memcmp::eq(signature.as_bytes(), my_signature.as_bytes()) {
-        return Err("invalid signature".into())
+    let my_signature = format!("{}{}", prefix, my_signature.to_hex());
+    // `memcmp::eq` panics on slices of different lengths
+    if signature.len() != my_signature.len() ||
+       !openssl::crypto::memcmp::eq(signature.as_bytes(),
+                                    my_signature.as_bytes()) {
+        return Err(BorsErrorKind::InvalidSignature.into())
     }
 
-    try!(Event::insert(tx, Provider::GitHub, &id, &event,
-                       try!(str::from_utf8(&body))));
+    try!(Event::insert(tx, project.id, Provider::GitHub, &id, &event,
+                       payload));
     Ok(util::html(""))
 }
 
 fn travis_webhook(req: &mut Request) -> BorsResult<Response> {
-    let slug = req.headers().find("Travis-Repo-Slug")
-                  .expect("slug not present")[0].to_string();
-    let signature = req.headers().find("Signature")
-                       .expect("signature not present")[0].to_string();
+    let slug = try!(header(req, "Travis-Repo-Slug"));
+    let signature = try!(header(req, "Signature"));
     let mut body = Vec::new();
     try!(req.body().read_to_end(&mut body));
     let query = url::form_urlencoded::parse(&body).collect::<Vec<_>>();
@@ -411,20 +1011,9 @@ fn travis_webhook(req: &mut Request) -> BorsResult<Response> {
         "signature was not valid base64"
     }));
 
-    let url = "https://api.travis-ci.org/config";
-    let config: travis::GetConfig = try!(http::get(url, &[]).chain_err(|| {
-        "failed to get travis config"
-    }));
-    let key = config.config.notifications.webhook.public_key;
-    let key = try!(PKey::public_key_from_pem(&key.as_bytes()).chain_err(|| {
-        "key was not valid pem"
-    }));
-    let rsa = try!(key.get_rsa().chain_err(|| "not an rsa key"));
-    println!("{:?}", signature);
-    println!("{:?}", payload);
-    try!(rsa.verify(Type::SHA1, payload.as_bytes(), &signature).chain_err(|| {
-        "invalid signature"
-    }));
+    try!(req.app().travis_keys.verify(&*req.app().http,
+                                      payload.as_bytes(),
+                                      &signature));
 
     let mut parts = slug.splitn(2, '/');
     let repo_user = parts.next().unwrap();
@@ -432,41 +1021,93 @@ fn travis_webhook(req: &mut Request) -> BorsResult<Response> {
 
     // Verify this is one of our projects
     let project = try!(Project::find_by_name(try!(req.tx()),
+                                             &req.app().encryption_key,
                                              repo_user,
                                              repo_name));
-    drop(project);
 
-    try!(Event::insert(try!(req.tx()), Provider::Travis, "", "", payload));
+    // Travis doesn't identify its deliveries, but each build only finishes in
+    // a given state once.
+    let build: travis::Payload = try!(json::decode(payload));
+    let id = format!("{}-{}", build.id, build.state);
+    try!(Event::insert(try!(req.tx()), project.id, Provider::Travis, &id, "",
+                       payload));
 
     Ok(util::html(""))
 }
 
 fn appveyor_webhook(req: &mut Request) -> BorsResult<Response> {
-    panic!()
-}
+    let mut body = Vec::new();
+    try!(req.body().read_to_end(&mut body));
+    let body = try!(str::from_utf8(&body));
 
-fn site_html(req: &Request, body: &str) -> Response {
-    let mut page = format!(r#"
-<html>
-<head>
-<link href="/assets/site.css" rel=stylesheet>
-</head>
-<body>
-    "#);
+    let project = try!(req_project(req));
+    let token = match project.appveyor_token {
+        Some(ref token) => token,
+        None => return Err("appveyor is not configured for this project".into()),
+    };
 
-    if let Some(error) = req.flash_error() {
-        page.push_str(&format!("\
-            <div class='flash error'>{}</div>
-        ", handlebars::html_escape(error)));
+    let notification: appveyor::Notification = try!(json::decode(body));
+    let build = &notification.eventData;
+    let repo_name = format!("{}/{}", project.repo_user, project.repo_name);
+    if build.repositoryName != repo_name {
+        return Err("appveyor build is for a different repository".into())
     }
 
-    page.push_str(body);
-    page.push_str("
-</body>
-</html>
-");
+    // AppVeyor doesn't sign its webhooks, so authenticate the notification by
+    // asking AppVeyor itself whether the build actually looks like this.
+    let url = format!("/projects/{}/{}/build/{}",
+                      build.accountName,
+                      build.projectSlug,
+                      build.buildVersion);
+    let actual: appveyor::GetBuild = try!(http::appveyor_get(req.app(), &url,
+                                                             token));
+    if actual.project.repositoryName != repo_name {
+        return Err("appveyor project is for a different repository".into())
+    }
+    if actual.build.buildId != build.buildId ||
+       actual.build.commitId != build.commitId ||
+       actual.build.status.to_lowercase() != build.status.to_lowercase() {
+        return Err("appveyor build does not match notification".into())
+    }
+    if !queue::is_bors_branch(&actual.build.branch) {
+        let msg = format!("appveyor build is on branch {}, which isn't \
+                           one of bors's", actual.build.branch);
+        return Err(BorsErrorKind::BadRequest(msg).into())
+    }
 
-    util::html(&page)
+    // Store the build as AppVeyor sees it, that's what the worker acts on.
+    let id = format!("{}-{}", actual.build.buildId,
+                     actual.build.status.to_lowercase());
+    let verified = try!(json::encode(&actual));
+    try!(Event::insert(try!(req.tx()), project.id, Provider::AppVeyor,
+                       &id, &notification.eventName, &verified));
+    Ok(util::html(""))
+}
+
+/// Renders `template` with `data` inside of the site's layout.
+fn render<T: Encodable>(req: &mut Request,
+                        template: &str,
+                        data: &T) -> BorsResult<Response> {
+    let login = req.session().get("github_login").cloned();
+    let csrf_token = csrf_token(req);
+    let templates = &req.app().templates;
+    let mut data = try!(Json::from_str(&try!(json::encode(data))));
+    if let Json::Object(ref mut data) = data {
+        data.insert("csrf_token".to_string(), csrf_token.to_json());
+    }
+    let body = try!(templates.render(template, &data));
+
+    let mut layout = BTreeMap::new();
+    layout.insert("body".to_string(), body.to_json());
+    layout.insert("csrf_token".to_string(), csrf_token.to_json());
+    if let Some(error) = req.flash_error() {
+        layout.insert("flash_error".to_string(), error.to_json());
+    }
+    if let Some(login) = login {
+        layout.insert("login".to_string(), login.to_json());
+    }
+    let page = try!(templates.render("layout", &layout));
+    Ok(util::html(&page))
 }
 
 pub struct R404(pub RouteBuilder);
@@ -477,12 +1118,23 @@ impl Handler for R404 {
         let res = match router.recognize(&req.method(), req.path()) {
             Ok(m) => {
                 req.mut_extensions().insert(m.params.clone());
-                m.handler.call(req)
+                // Webhooks are authenticated by their signatures instead.
+                let checked = if req.method() == Method::Post &&
+                                 !req.path().starts_with("/webhook/") {
+                    check_csrf_token(req)
+                } else {
+                    Ok(())
+                };
+                match checked {
+                    Ok(()) => m.handler.call(req),
+                    Err(e) => Err(Box::new(e) as Box<_>),
+                }
             }
             Err(_) => {
-                let mut response = site_html(req, "page not found");
-                response.status = (404, "Not Found");
-                Ok(response)
+                render(req, "not_found", &()).map(|mut response| {
+                    response.status = (404, "Not Found");
+                    response
+                }).map_err(|e| Box::new(e) as Box<_>)
             }
         };
 
@@ -501,6 +1153,24 @@ impl Handler for R404 {
                 req.set_flash_error("user/repo combo not found");
                 repos(req).map_err(|e| Box::new(e) as Box<_>)
             }
+            BorsErrorKind::NotLoggedIn => {
+                req.set_flash_error("you need to log in first");
+                repos(req).map_err(|e| Box::new(e) as Box<_>)
+            }
+            BorsErrorKind::Forbidden => {
+                req.set_flash_error("you need push access to that repository");
+                repos(req).map_err(|e| Box::new(e) as Box<_>)
+            }
+            BorsErrorKind::BadRequest(ref msg) => {
+                Ok(util::error(400, "Bad Request", msg))
+            }
+            BorsErrorKind::InvalidSignature => {
+                Ok(util::error(401, "Unauthorized", "invalid signature"))
+            }
+            BorsErrorKind::RateLimited(..) => {
+                Ok(util::error(503, "Service Unavailable",
+                               "rate limited by the api, try again later"))
+            }
             _ => {
                 {
                     error!("top-level error: {}", err);
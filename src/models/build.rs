@@ -0,0 +1,70 @@
+use pg::GenericConnection;
+use pg::rows::Row;
+
+use errors::*;
+use models::{Provider, Status};
+
+/// The result of one CI provider testing one merge commit.
+pub struct Build {
+    pub id: i32,
+    pub pull_request_id: i32,
+    pub provider_id: Provider,
+    pub sha: String,
+    pub status: Status,
+    pub url: Option<String>,
+}
+
+impl Build {
+    /// Records the latest status reported by `provider` for `sha`, replacing
+    /// any status it reported previously.
+    pub fn record(conn: &GenericConnection,
+                  pull_request_id: i32,
+                  provider: Provider,
+                  sha: &str,
+                  status: Status,
+                  url: &str) -> BorsResult<Build> {
+        let stmt = try!(conn.prepare("INSERT INTO builds
+                                      (pull_request_id,
+                                       provider_id,
+                                       sha,
+                                       status,
+                                       url)
+                                      VALUES ($1, $2, $3, $4, $5)
+                                      ON CONFLICT
+                                        (pull_request_id, provider_id, sha)
+                                      DO UPDATE SET status = EXCLUDED.status,
+                                                    url = EXCLUDED.url
+                                      RETURNING *"));
+        let rows = try!(stmt.query(&[&pull_request_id,
+                                     &(provider as i32),
+                                     &sha,
+                                     &(status as i32),
+                                     &url]));
+        Ok(Build::from_row(&rows.iter().next().unwrap()))
+    }
+
+    pub fn find_by_sha(conn: &GenericConnection,
+                       pull_request_id: i32,
+                       sha: &str) -> BorsResult<Vec<Build>> {
+        let stmt = try!(conn.prepare("SELECT * FROM builds
+                                      WHERE pull_request_id = $1 AND sha = $2"));
+        let rows = try!(stmt.query(&[&pull_request_id, &sha]));
+        Ok(rows.iter().map(|r| Build::from_row(&r)).collect())
+    }
+
+    pub fn from_row(row: &Row) -> Build {
+        Build {
+            id: row.get("id"),
+            pull_request_id: row.get("pull_request_id"),
+            provider_id: match row.get("provider_id") {
+                0 => Provider::GitHub,
+                1 => Provider::Travis,
+                2 => Provider::AppVeyor,
+                n => panic!("invalid id: {}", n),
+            },
+            sha: row.get("sha"),
+            status: Status::from_i32(row.get("status")),
+            url: row.get("url"),
+        }
+    }
+}
@@ -1,4 +1,3 @@
-
 use pg::GenericConnection;
 use pg::rows::Row;
 
@@ -6,42 +5,145 @@ use errors::*;
 
 pub struct Event {
     pub id: i32,
+    pub project_id: Option<i32>,
     pub provider_id: Provider,
     pub provider_event_id: String,
     pub provider_event: String,
     pub event: String,
+    pub state: State,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Provider {
     GitHub,
     Travis,
     AppVeyor,
 }
 
+/// Processing state of an event, stored in the `state` column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Unprocessed,
+    Processed,
+    Failed,
+}
+
 impl Event {
+    /// Stores a new unprocessed event.
+    ///
+    /// Providers redeliver events, so if the project already has an event
+    /// with the same provider and id this does nothing and returns `None`.
     pub fn insert(conn: &GenericConnection,
+                  project_id: i32,
                   provider: Provider,
                   provider_event_id: &str,
                   provider_event: &str,
-                  event: &str) -> BorsResult<Event> {
+                  event: &str) -> BorsResult<Option<Event>> {
         let stmt = try!(conn.prepare("INSERT INTO events
-                                      (provider_id,
+                                      (project_id,
+                                       provider_id,
                                        provider_event_id,
                                        provider_event,
                                        event,
                                        state)
-                                      VALUES ($1, $2, $3, $4, 0)
+                                      VALUES ($1, $2, $3, $4, $5, $6)
+                                      ON CONFLICT (project_id,
+                                                   provider_id,
+                                                   provider_event_id)
+                                      DO NOTHING
                                       RETURNING *"));
-        let rows = try!(stmt.query(&[&(provider as i32),
+        let rows = try!(stmt.query(&[&project_id,
+                                     &(provider as i32),
                                      &provider_event_id,
                                      &provider_event,
-                                     &event]));
-        Ok(Event::from_row(&rows.iter().next().unwrap()))
+                                     &event,
+                                     &(State::Unprocessed as i32)]));
+        Ok(rows.iter().next().map(|r| Event::from_row(&r)))
+    }
+
+    /// Claims the oldest unprocessed event, if any.
+    ///
+    /// The returned row is locked with `FOR UPDATE` for the rest of the
+    /// transaction `conn` is part of, and rows locked by other workers are
+    /// skipped, so any number of workers can run this concurrently without
+    /// processing an event twice.
+    pub fn claim_next(conn: &GenericConnection) -> BorsResult<Option<Event>> {
+        let stmt = try!(conn.prepare("SELECT * FROM events
+                                      WHERE state = $1
+                                        AND (retry_at IS NULL OR
+                                             retry_at <= now())
+                                      ORDER BY id
+                                      LIMIT 1
+                                      FOR UPDATE SKIP LOCKED"));
+        let rows = try!(stmt.query(&[&(State::Unprocessed as i32)]));
+        Ok(rows.iter().next().map(|r| Event::from_row(&r)))
+    }
+
+    pub fn find(conn: &GenericConnection, id: i32) -> BorsResult<Option<Event>> {
+        let stmt = try!(conn.prepare("SELECT * FROM events WHERE id = $1"));
+        let rows = try!(stmt.query(&[&id]));
+        Ok(rows.iter().next().map(|r| Event::from_row(&r)))
+    }
+
+    /// Returns the latest `limit` events of a project, newest first, only
+    /// including those which match all of the filters given.
+    pub fn recent(conn: &GenericConnection,
+                  project_id: i32,
+                  provider: Option<Provider>,
+                  provider_event: Option<&str>,
+                  state: Option<State>,
+                  limit: i64) -> BorsResult<Vec<Event>> {
+        let stmt = try!(conn.prepare("SELECT * FROM events
+                                      WHERE project_id = $1
+                                        AND ($2::INTEGER IS NULL OR
+                                             provider_id = $2)
+                                        AND ($3::VARCHAR IS NULL OR
+                                             provider_event = $3)
+                                        AND ($4::INTEGER IS NULL OR
+                                             state = $4)
+                                      ORDER BY id DESC
+                                      LIMIT $5"));
+        let rows = try!(stmt.query(&[&project_id,
+                                     &provider.map(|p| p as i32),
+                                     &provider_event,
+                                     &state.map(|s| s as i32),
+                                     &limit]));
+        Ok(rows.iter().map(|r| Event::from_row(&r)).collect())
+    }
+
+    /// Leaves this event unprocessed, but keeps `claim_next` from handing it
+    /// out again for `secs` seconds.
+    pub fn postpone(&self,
+                    conn: &GenericConnection,
+                    secs: u64) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("UPDATE events
+                                         SET retry_at = now() +
+                                                        interval '1 second' * $1
+                                       WHERE id = $2"));
+        try!(stmt.execute(&[&(secs as f64), &self.id]));
+        Ok(())
+    }
+
+    /// Moves the event to `state`, which clears `processed_at` if the event
+    /// is to be processed again.
+    pub fn set_state(&self,
+                     conn: &GenericConnection,
+                     state: State) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("UPDATE events
+                                         SET state = $1,
+                                             processed_at =
+                                               CASE WHEN $2 THEN now() END,
+                                             retry_at = NULL
+                                       WHERE id = $3"));
+        let processed = state != State::Unprocessed;
+        try!(stmt.execute(&[&(state as i32), &processed, &self.id]));
+        Ok(())
     }
 
     pub fn from_row(row: &Row) -> Event {
         Event {
             id: row.get("id"),
+            project_id: row.get("project_id"),
             provider_id: match row.get("provider_id") {
                 0 => Provider::GitHub,
                 1 => Provider::Travis,
@@ -51,6 +153,12 @@ impl Event {
             provider_event_id: row.get("provider_event_id"),
             provider_event: row.get("provider_event"),
             event: row.get("event"),
+            state: match row.get("state") {
+                0 => State::Unprocessed,
+                1 => State::Processed,
+                2 => State::Failed,
+                n => panic!("invalid state: {}", n),
+            },
         }
     }
 }
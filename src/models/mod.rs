@@ -1,5 +1,13 @@
 pub use self::project::*;
 pub use self::event::*;
+pub use self::pull_request::*;
+pub use self::build::*;
+pub use self::user::*;
+pub use self::reviewer::*;
 
 mod project;
 mod event;
+mod pull_request;
+mod build;
+mod user;
+mod reviewer;
@@ -1,6 +1,7 @@
 use pg::GenericConnection;
 use pg::rows::Row;
 
+use crypto::Key;
 use errors::*;
 
 pub struct Project {
@@ -10,12 +11,20 @@ pub struct Project {
     pub github_repo_id: i32,
     pub github_access_token: String,
     pub github_webhook_secret: String,
+    /// The id of the webhook bors2 created, unknown for projects added
+    /// before it was recorded.
+    pub github_webhook_id: Option<i32>,
+    /// What the last check of the webhook found, see `hooks::check`.
+    pub github_webhook_status: Option<String>,
     pub appveyor_token: Option<String>,
     pub travis_access_token: Option<String>,
 }
 
 impl Project {
-    pub fn insert(conn: &GenericConnection,
+    /// Registers a repository, or updates its project if it's already
+    /// registered.
+    pub fn upsert(conn: &GenericConnection,
+                  key: &Key,
                   repo_user: &str,
                   repo_name: &str,
                   github_repo_id: i32,
@@ -28,16 +37,36 @@ impl Project {
                                        github_access_token,
                                        github_webhook_secret)
                                       VALUES ($1, $2, $3, $4, $5)
+                                      ON CONFLICT (github_repo_id) DO UPDATE
+                                         SET repo_user = excluded.repo_user,
+                                             repo_name = excluded.repo_name,
+                                             github_access_token =
+                                                 excluded.github_access_token,
+                                             github_webhook_secret =
+                                                 excluded.github_webhook_secret
                                       RETURNING *"));
         let rows = try!(stmt.query(&[&repo_user,
                                      &repo_name,
                                      &github_repo_id,
-                                     &github_access_token,
-                                     &github_webhook_secret]));
-        Ok(Project::from_row(&rows.iter().next().unwrap()))
+                                     &try!(key.encrypt(github_access_token)),
+                                     &try!(key.encrypt(github_webhook_secret))]));
+        Project::from_row(&rows.iter().next().unwrap(), key)
+    }
+
+    pub fn find(conn: &GenericConnection,
+                key: &Key,
+                id: i32) -> BorsResult<Project> {
+        let stmt = try!(conn.prepare("SELECT * FROM projects
+                                      WHERE id = $1"));
+        let rows = try!(stmt.query(&[&id]));
+        match rows.into_iter().next() {
+            Some(ref p) => Project::from_row(p, key),
+            None => Err(BorsErrorKind::MissingProject.into()),
+        }
     }
 
     pub fn find_by_name(conn: &GenericConnection,
+                        key: &Key,
                         user: &str,
                         repo: &str) -> BorsResult<Project> {
         let stmt = try!(conn.prepare("SELECT * FROM projects
@@ -45,47 +74,150 @@ impl Project {
                                       LIMIT 1"));
         let rows = try!(stmt.query(&[&user, &repo]));
         match rows.into_iter().next() {
-            Some(ref p) => Ok(Project::from_row(p)),
+            Some(ref p) => Project::from_row(p, key),
             None => Err(BorsErrorKind::MissingProject.into()),
         }
     }
 
-    pub fn all(conn: &GenericConnection) -> BorsResult<Vec<Project>> {
+    /// Finds a project by its repository's id, which unlike its name
+    /// survives renames and transfers.
+    pub fn find_by_github_id(conn: &GenericConnection,
+                             key: &Key,
+                             github_repo_id: i32)
+                             -> BorsResult<Option<Project>> {
+        let stmt = try!(conn.prepare("SELECT * FROM projects
+                                      WHERE github_repo_id = $1"));
+        let rows = try!(stmt.query(&[&github_repo_id]));
+        match rows.into_iter().next() {
+            Some(ref p) => Project::from_row(p, key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn all(conn: &GenericConnection,
+               key: &Key) -> BorsResult<Vec<Project>> {
         let stmt = try!(conn.prepare("SELECT * FROM projects"));
         let rows = try!(stmt.query(&[]));
-        Ok(rows.iter().map(|r| Project::from_row(&r)).collect())
+        // A project which can't be decrypted shouldn't take every other
+        // project down with it.
+        Ok(rows.iter().filter_map(|r| {
+            match Project::from_row(&r, key) {
+                Ok(project) => Some(project),
+                Err(e) => {
+                    let id: i32 = r.get("id");
+                    error!("failed to load project {}: {}", id, e);
+                    None
+                }
+            }
+        }).collect())
     }
 
     pub fn set_travis_token(&self,
                             conn: &GenericConnection,
+                            key: &Key,
                             token: &str) -> BorsResult<()> {
         let stmt = try!(conn.prepare("UPDATE projects
                                          SET travis_access_token = $1
                                        WHERE id = $2"));
-        try!(stmt.query(&[&token, &self.id]));
+        try!(stmt.query(&[&try!(key.encrypt(token)), &self.id]));
         Ok(())
     }
 
     pub fn set_appveyor_token(&self,
                               conn: &GenericConnection,
+                              key: &Key,
                               token: &str) -> BorsResult<()> {
         let stmt = try!(conn.prepare("UPDATE projects
                                          SET appveyor_token = $1
                                        WHERE id = $2"));
-        try!(stmt.query(&[&token, &self.id]));
+        try!(stmt.query(&[&try!(key.encrypt(token)), &self.id]));
+        Ok(())
+    }
+
+    pub fn rename(&self,
+                  conn: &GenericConnection,
+                  repo_user: &str,
+                  repo_name: &str) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("UPDATE projects
+                                         SET repo_user = $1,
+                                             repo_name = $2
+                                       WHERE id = $3"));
+        try!(stmt.query(&[&repo_user, &repo_name, &self.id]));
+        Ok(())
+    }
+
+    pub fn set_github_webhook(&self,
+                              conn: &GenericConnection,
+                              id: i32,
+                              status: &str) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("UPDATE projects
+                                         SET github_webhook_id = $1,
+                                             github_webhook_status = $2
+                                       WHERE id = $3"));
+        try!(stmt.query(&[&id, &status, &self.id]));
+        Ok(())
+    }
+
+    pub fn set_github_webhook_status(&self,
+                                     conn: &GenericConnection,
+                                     status: &str) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("UPDATE projects
+                                         SET github_webhook_status = $1
+                                       WHERE id = $2"));
+        try!(stmt.query(&[&status, &self.id]));
         Ok(())
     }
 
-    pub fn from_row(row: &Row) -> Project {
-        Project {
+    /// Deletes this project along with its pull requests, builds, reviewers
+    /// and events. The CI tokens are stored on the project, so they go too.
+    pub fn delete(&self, conn: &GenericConnection) -> BorsResult<()> {
+        let queries = [
+            "DELETE FROM builds WHERE pull_request_id IN
+             (SELECT id FROM pull_requests WHERE project_id = $1)",
+            "DELETE FROM pull_requests WHERE project_id = $1",
+            "DELETE FROM reviewers WHERE project_id = $1",
+            "DELETE FROM events WHERE project_id = $1",
+            "DELETE FROM projects WHERE id = $1",
+        ];
+        for query in queries.iter() {
+            let stmt = try!(conn.prepare(query));
+            try!(stmt.execute(&[&self.id]));
+        }
+        Ok(())
+    }
+
+    /// Locks this project's row until the end of the current transaction.
+    ///
+    /// Used to make sure only one worker at a time drives a project's merge
+    /// queue.
+    pub fn lock(&self, conn: &GenericConnection) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("SELECT id FROM projects
+                                      WHERE id = $1
+                                      FOR UPDATE"));
+        try!(stmt.query(&[&self.id]));
+        Ok(())
+    }
+
+    pub fn from_row(row: &Row, key: &Key) -> BorsResult<Project> {
+        let decrypt = |column: &str| -> BorsResult<Option<String>> {
+            match row.get::<_, Option<String>>(column) {
+                Some(value) => key.decrypt(&value).map(Some),
+                None => Ok(None),
+            }
+        };
+        Ok(Project {
             id: row.get("id"),
             repo_user: row.get("repo_user"),
             repo_name: row.get("repo_name"),
             github_repo_id: row.get("github_repo_id"),
-            github_access_token: row.get("github_access_token"),
-            github_webhook_secret: row.get("github_webhook_secret"),
-            appveyor_token: row.get("appveyor_token"),
-            travis_access_token: row.get("travis_access_token"),
-        }
+            github_access_token:
+                try!(decrypt("github_access_token")).unwrap(),
+            github_webhook_secret:
+                try!(decrypt("github_webhook_secret")).unwrap(),
+            github_webhook_id: row.get("github_webhook_id"),
+            github_webhook_status: row.get("github_webhook_status"),
+            appveyor_token: try!(decrypt("appveyor_token")),
+            travis_access_token: try!(decrypt("travis_access_token")),
+        })
     }
 }
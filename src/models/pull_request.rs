@@ -0,0 +1,340 @@
+use pg::GenericConnection;
+use pg::rows::Row;
+
+use errors::*;
+use github;
+
+pub struct PullRequest {
+    pub id: i32,
+    pub project_id: i32,
+    pub number: i32,
+    pub github_id: i64,
+    pub status: Status,
+    pub head_ref: String,
+    pub head_commit: String,
+    pub base_ref: String,
+    pub merge_sha: Option<String>,
+    pub title: String,
+    pub author: String,
+    pub approved_by: Option<String>,
+    pub delegate: Option<String>,
+    pub mergeable: Option<bool>,
+    pub assignee: Option<String>,
+    pub priority: i32,
+    pub rollup: bool,
+    pub try_requested: bool,
+    pub try_status: Status,
+    pub try_sha: Option<String>,
+    /// The rollup pull request this one is being tested as part of.
+    pub rollup_id: Option<i32>,
+    pub open: bool,
+}
+
+/// Status of the latest bors build of a pull request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Idle,
+    Pending,
+    Success,
+    Failure,
+    Error,
+}
+
+impl Status {
+    pub fn from_i32(n: i32) -> Status {
+        match n {
+            0 => Status::Idle,
+            1 => Status::Pending,
+            2 => Status::Success,
+            3 => Status::Failure,
+            4 => Status::Error,
+            n => panic!("invalid status: {}", n),
+        }
+    }
+}
+
+impl PullRequest {
+    pub fn insert(conn: &GenericConnection,
+                  project_id: i32,
+                  pr: &github::PullRequest) -> BorsResult<PullRequest> {
+        let stmt = try!(conn.prepare("INSERT INTO pull_requests
+                                      (project_id,
+                                       number,
+                                       github_id,
+                                       status,
+                                       head_ref,
+                                       head_commit,
+                                       title,
+                                       author,
+                                       mergeable,
+                                       assignee,
+                                       priority,
+                                       rollup,
+                                       try_requested,
+                                       open,
+                                       base_ref)
+                                      VALUES ($1, $2, $3, $4, $5, $6, $7, $8,
+                                              $9, $10, 0, false, false, $11,
+                                              $12)
+                                      RETURNING *"));
+        let assignee = pr.assignee.as_ref().map(|a| &a.login[..]);
+        let rows = try!(stmt.query(&[&project_id,
+                                     &pr.number,
+                                     &pr.id,
+                                     &(Status::Idle as i32),
+                                     &pr.head.ref_,
+                                     &pr.head.sha,
+                                     &pr.title,
+                                     &pr.user.login,
+                                     &pr.mergeable,
+                                     &assignee,
+                                     &(pr.state == "open"),
+                                     &pr.base.ref_]));
+        Ok(PullRequest::from_row(&rows.iter().next().unwrap()))
+    }
+
+    pub fn find(conn: &GenericConnection, id: i32) -> BorsResult<PullRequest> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE id = $1"));
+        let rows = try!(stmt.query(&[&id]));
+        match rows.into_iter().next() {
+            Some(ref p) => Ok(PullRequest::from_row(p)),
+            None => Err(format!("pull request {} not found", id).into()),
+        }
+    }
+
+    pub fn find_by_number(conn: &GenericConnection,
+                          project_id: i32,
+                          number: i32) -> BorsResult<Option<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1 AND number = $2
+                                      LIMIT 1"));
+        let rows = try!(stmt.query(&[&project_id, &number]));
+        Ok(rows.iter().next().map(|r| PullRequest::from_row(&r)))
+    }
+
+    /// Returns the pull request currently being tested on the `auto` branch,
+    /// if any.
+    pub fn find_pending(conn: &GenericConnection,
+                        project_id: i32) -> BorsResult<Option<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1 AND status = $2
+                                      LIMIT 1"));
+        let rows = try!(stmt.query(&[&project_id, &(Status::Pending as i32)]));
+        Ok(rows.iter().next().map(|r| PullRequest::from_row(&r)))
+    }
+
+    pub fn find_by_merge_sha(conn: &GenericConnection,
+                             project_id: i32,
+                             sha: &str) -> BorsResult<Option<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1 AND merge_sha = $2
+                                      LIMIT 1"));
+        let rows = try!(stmt.query(&[&project_id, &sha]));
+        Ok(rows.iter().next().map(|r| PullRequest::from_row(&r)))
+    }
+
+    /// Returns the pull request currently being tested on the `try` branch,
+    /// if any.
+    pub fn find_pending_try(conn: &GenericConnection,
+                            project_id: i32)
+                            -> BorsResult<Option<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1
+                                        AND try_status = $2
+                                      LIMIT 1"));
+        let rows = try!(stmt.query(&[&project_id, &(Status::Pending as i32)]));
+        Ok(rows.iter().next().map(|r| PullRequest::from_row(&r)))
+    }
+
+    pub fn find_by_try_sha(conn: &GenericConnection,
+                           project_id: i32,
+                           sha: &str) -> BorsResult<Option<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1 AND try_sha = $2
+                                      LIMIT 1"));
+        let rows = try!(stmt.query(&[&project_id, &sha]));
+        Ok(rows.iter().next().map(|r| PullRequest::from_row(&r)))
+    }
+
+    /// Returns the pull request which should get a try build next.
+    pub fn next_try(conn: &GenericConnection,
+                    project_id: i32) -> BorsResult<Option<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1
+                                        AND open
+                                        AND try_requested
+                                      ORDER BY priority DESC, number ASC
+                                      LIMIT 1"));
+        let rows = try!(stmt.query(&[&project_id]));
+        Ok(rows.iter().next().map(|r| PullRequest::from_row(&r)))
+    }
+
+    /// Returns the approved pull request which should be tested next: the
+    /// highest priority one, with ties going to the oldest.
+    pub fn next_in_queue(conn: &GenericConnection,
+                         project_id: i32) -> BorsResult<Option<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1
+                                        AND open
+                                        AND approved_by IS NOT NULL
+                                        AND status = $2
+                                        AND mergeable IS NOT false
+                                        AND rollup_id IS NULL
+                                      ORDER BY priority DESC, number ASC
+                                      LIMIT 1"));
+        let rows = try!(stmt.query(&[&project_id, &(Status::Idle as i32)]));
+        Ok(rows.iter().next().map(|r| PullRequest::from_row(&r)))
+    }
+
+    /// Returns all open pull requests in the order the queue will get to
+    /// them: the one being tested, then the approved ones, then the rest.
+    pub fn queue(conn: &GenericConnection,
+                 project_id: i32) -> BorsResult<Vec<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1 AND open
+                                      ORDER BY status = $2 DESC,
+                                               (approved_by IS NOT NULL
+                                                AND status = $3
+                                                AND mergeable IS NOT false
+                                                AND rollup_id IS NULL) DESC,
+                                               priority DESC,
+                                               number ASC"));
+        let rows = try!(stmt.query(&[&project_id,
+                                     &(Status::Pending as i32),
+                                     &(Status::Idle as i32)]));
+        Ok(rows.iter().map(|r| PullRequest::from_row(&r)).collect())
+    }
+
+    /// Returns all approved pull requests marked `rollup` which aren't
+    /// already part of a rollup, in queue order.
+    pub fn rollup_candidates(conn: &GenericConnection,
+                             project_id: i32) -> BorsResult<Vec<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE project_id = $1
+                                        AND open
+                                        AND rollup
+                                        AND approved_by IS NOT NULL
+                                        AND status = $2
+                                        AND mergeable IS NOT false
+                                        AND rollup_id IS NULL
+                                      ORDER BY priority DESC, number ASC"));
+        let rows = try!(stmt.query(&[&project_id, &(Status::Idle as i32)]));
+        Ok(rows.iter().map(|r| PullRequest::from_row(&r)).collect())
+    }
+
+    /// Returns the pull requests included in the rollup `rollup_id`.
+    pub fn find_by_rollup(conn: &GenericConnection,
+                          rollup_id: i32) -> BorsResult<Vec<PullRequest>> {
+        let stmt = try!(conn.prepare("SELECT * FROM pull_requests
+                                      WHERE rollup_id = $1
+                                      ORDER BY number"));
+        let rows = try!(stmt.query(&[&rollup_id]));
+        Ok(rows.iter().map(|r| PullRequest::from_row(&r)).collect())
+    }
+
+    /// Puts the pull requests of a rollup which won't land back in the queue.
+    pub fn release_rollup(conn: &GenericConnection,
+                          rollup_id: i32) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("UPDATE pull_requests
+                                         SET rollup_id = NULL
+                                       WHERE rollup_id = $1
+                                         AND status != $2"));
+        try!(stmt.execute(&[&rollup_id, &(Status::Success as i32)]));
+        Ok(())
+    }
+
+    /// Writes all of the mutable state of this pull request back to the
+    /// database.
+    pub fn update(&self, conn: &GenericConnection) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("UPDATE pull_requests
+                                         SET status = $1,
+                                             head_ref = $2,
+                                             head_commit = $3,
+                                             title = $4,
+                                             approved_by = $5,
+                                             delegate = $6,
+                                             mergeable = $7,
+                                             assignee = $8,
+                                             priority = $9,
+                                             rollup = $10,
+                                             try_requested = $11,
+                                             open = $12,
+                                             base_ref = $13,
+                                             merge_sha = $14,
+                                             try_status = $15,
+                                             try_sha = $16,
+                                             rollup_id = $17
+                                       WHERE id = $18"));
+        try!(stmt.execute(&[&(self.status as i32),
+                            &self.head_ref,
+                            &self.head_commit,
+                            &self.title,
+                            &self.approved_by,
+                            &self.delegate,
+                            &self.mergeable,
+                            &self.assignee,
+                            &self.priority,
+                            &self.rollup,
+                            &self.try_requested,
+                            &self.open,
+                            &self.base_ref,
+                            &self.merge_sha,
+                            &(self.try_status as i32),
+                            &self.try_sha,
+                            &self.rollup_id,
+                            &self.id]));
+        Ok(())
+    }
+
+    /// Updates our view of this pull request with fresh information from
+    /// GitHub.
+    ///
+    /// If new commits have been pushed then any previous approval and build
+    /// results no longer apply and are reset.
+    pub fn sync(&mut self, pr: &github::PullRequest) {
+        if self.head_commit != pr.head.sha {
+            self.head_commit = pr.head.sha.clone();
+            self.approved_by = None;
+            self.status = Status::Idle;
+            self.try_requested = false;
+            self.try_status = Status::Idle;
+        }
+        self.head_ref = pr.head.ref_.clone();
+        self.base_ref = pr.base.ref_.clone();
+        self.title = pr.title.clone();
+        self.mergeable = pr.mergeable;
+        self.assignee = pr.assignee.as_ref().map(|a| a.login.clone());
+        self.open = pr.state == "open";
+        if !self.open && self.status == Status::Pending {
+            self.status = Status::Idle;
+        }
+    }
+
+    pub fn from_row(row: &Row) -> PullRequest {
+        PullRequest {
+            id: row.get("id"),
+            project_id: row.get("project_id"),
+            number: row.get("number"),
+            github_id: row.get("github_id"),
+            status: Status::from_i32(row.get("status")),
+            head_ref: row.get("head_ref"),
+            head_commit: row.get("head_commit"),
+            base_ref: row.get("base_ref"),
+            merge_sha: row.get("merge_sha"),
+            title: row.get("title"),
+            author: row.get("author"),
+            approved_by: row.get("approved_by"),
+            delegate: row.get("delegate"),
+            mergeable: row.get("mergeable"),
+            assignee: row.get("assignee"),
+            priority: row.get("priority"),
+            rollup: row.get("rollup"),
+            try_requested: row.get("try_requested"),
+            try_status: Status::from_i32(row.get("try_status")),
+            try_sha: row.get("try_sha"),
+            rollup_id: row.get("rollup_id"),
+            open: row.get("open"),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use pg::GenericConnection;
+use pg::rows::Row;
+
+use errors::*;
+
+/// A GitHub user who is allowed to give commands to bors on a project.
+pub struct Reviewer {
+    pub id: i32,
+    pub project_id: i32,
+    pub login: String,
+    pub permission: Permission,
+}
+
+/// What a reviewer may do, stored in the `permission` column.
+///
+/// Reviewers may also start try builds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Try,
+    Review,
+}
+
+impl Permission {
+    pub fn from_i32(n: i32) -> Permission {
+        match n {
+            0 => Permission::Try,
+            1 => Permission::Review,
+            n => panic!("invalid permission: {}", n),
+        }
+    }
+}
+
+impl Reviewer {
+    /// Gives `login` `permission` on a project, replacing whatever they were
+    /// allowed to do before.
+    ///
+    /// GitHub logins are case insensitive, so they're stored in lower case.
+    pub fn set(conn: &GenericConnection,
+               project_id: i32,
+               login: &str,
+               permission: Permission) -> BorsResult<Reviewer> {
+        let stmt = try!(conn.prepare("INSERT INTO reviewers
+                                      (project_id, login, permission)
+                                      VALUES ($1, $2, $3)
+                                      ON CONFLICT (project_id, lower(login))
+                                      DO UPDATE SET permission =
+                                                      EXCLUDED.permission
+                                      RETURNING *"));
+        let rows = try!(stmt.query(&[&project_id,
+                                     &login.to_lowercase(),
+                                     &(permission as i32)]));
+        Ok(Reviewer::from_row(&rows.iter().next().unwrap()))
+    }
+
+    pub fn remove(conn: &GenericConnection,
+                  project_id: i32,
+                  login: &str) -> BorsResult<()> {
+        let stmt = try!(conn.prepare("DELETE FROM reviewers
+                                      WHERE project_id = $1
+                                        AND lower(login) = lower($2)"));
+        try!(stmt.execute(&[&project_id, &login]));
+        Ok(())
+    }
+
+    pub fn all(conn: &GenericConnection,
+               project_id: i32) -> BorsResult<Vec<Reviewer>> {
+        let stmt = try!(conn.prepare("SELECT * FROM reviewers
+                                      WHERE project_id = $1
+                                      ORDER BY login"));
+        let rows = try!(stmt.query(&[&project_id]));
+        Ok(rows.iter().map(|r| Reviewer::from_row(&r)).collect())
+    }
+
+    /// Returns what `login` is allowed to do on a project, if anything.
+    ///
+    /// GitHub logins are case insensitive, so the lookup is as well.
+    pub fn permission(conn: &GenericConnection,
+                      project_id: i32,
+                      login: &str) -> BorsResult<Option<Permission>> {
+        let stmt = try!(conn.prepare("SELECT * FROM reviewers
+                                      WHERE project_id = $1
+                                        AND lower(login) = lower($2)"));
+        let rows = try!(stmt.query(&[&project_id, &login]));
+        Ok(rows.iter().next().map(|r| Reviewer::from_row(&r).permission))
+    }
+
+    pub fn from_row(row: &Row) -> Reviewer {
+        Reviewer {
+            id: row.get("id"),
+            project_id: row.get("project_id"),
+            login: row.get("login"),
+            permission: Permission::from_i32(row.get("permission")),
+        }
+    }
+}
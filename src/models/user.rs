@@ -0,0 +1,55 @@
+use pg::GenericConnection;
+use pg::rows::Row;
+
+use crypto::Key;
+use errors::*;
+
+/// Someone who has logged in to bors through GitHub.
+pub struct User {
+    pub id: i32,
+    pub github_id: i32,
+    pub login: String,
+    pub github_access_token: String,
+}
+
+impl User {
+    /// Records a login by the GitHub user `github_id`, refreshing the login
+    /// and access token of a user we've already seen.
+    pub fn upsert(conn: &GenericConnection,
+                  key: &Key,
+                  github_id: i32,
+                  login: &str,
+                  github_access_token: &str) -> BorsResult<User> {
+        let stmt = try!(conn.prepare("INSERT INTO users
+                                      (github_id, login, github_access_token)
+                                      VALUES ($1, $2, $3)
+                                      ON CONFLICT (github_id)
+                                      DO UPDATE SET login = EXCLUDED.login,
+                                                    github_access_token =
+                                                      EXCLUDED.github_access_token
+                                      RETURNING *"));
+        let rows = try!(stmt.query(&[&github_id,
+                                     &login,
+                                     &try!(key.encrypt(github_access_token))]));
+        User::from_row(&rows.iter().next().unwrap(), key)
+    }
+
+    pub fn find(conn: &GenericConnection, key: &Key, id: i32) -> BorsResult<User> {
+        let stmt = try!(conn.prepare("SELECT * FROM users WHERE id = $1"));
+        let rows = try!(stmt.query(&[&id]));
+        match rows.into_iter().next() {
+            Some(ref u) => User::from_row(u, key),
+            None => Err(BorsErrorKind::NotLoggedIn.into()),
+        }
+    }
+
+    pub fn from_row(row: &Row, key: &Key) -> BorsResult<User> {
+        let token: String = row.get("github_access_token");
+        Ok(User {
+            id: row.get("id"),
+            github_id: row.get("github_id"),
+            login: row.get("login"),
+            github_access_token: try!(key.decrypt(&token)),
+        })
+    }
+}
@@ -0,0 +1,602 @@
+//! The merge queue, which is what actually makes bors a bors.
+//!
+//! One approved pull request per project at a time is merged into its base
+//! branch on the `auto` branch. Once every CI provider configured for the
+//! project has passed that exact merge commit the base branch is
+//! fast-forwarded to it, so the base branch only ever contains commits which
+//! have passed CI.
+//!
+//! Try builds work the same way on the `try` branch, except that nothing is
+//! ever pushed to the base branch afterwards.
+//!
+//! Rollups merge many small approved pull requests into a temporary branch
+//! and open a new pull request for it, which then goes through the queue as
+//! usual. Once it lands, each pull request it included is marked as merged.
+
+use pg::GenericConnection;
+use rand::{Rng, thread_rng};
+
+use app::App;
+use errors::*;
+use github;
+use http;
+use models::*;
+
+/// The branch merge commits are tested on.
+pub const AUTO_BRANCH: &'static str = "auto";
+
+/// The branch try builds are tested on.
+pub const TRY_BRANCH: &'static str = "try";
+
+/// The prefix of the temporary branches rollups are built on.
+pub const ROLLUP_BRANCH_PREFIX: &'static str = "rollup-";
+
+/// The context of the commit statuses bors sets on pull requests.
+pub const STATUS_CONTEXT: &'static str = "continuous-integration/bors";
+
+/// Whether bors builds things on `branch`, so that CI results for it matter.
+pub fn is_bors_branch(branch: &str) -> bool {
+    branch == AUTO_BRANCH || branch == TRY_BRANCH ||
+        branch.starts_with(ROLLUP_BRANCH_PREFIX)
+}
+
+/// Starts testing the next pull request in `project`'s queue, and the next
+/// try build, unless they're already being tested.
+///
+/// Each of the two is done in its own savepoint so one failing doesn't undo
+/// or hold up the other, which means the caller should commit even if an
+/// error is returned.
+pub fn process(app: &App,
+               conn: &GenericConnection,
+               project: &Project) -> BorsResult<()> {
+    try!(project.lock(conn));
+
+    if required_providers(project).is_empty() {
+        debug!("no CI configured for {}/{}", project.repo_user,
+               project.repo_name);
+        return Ok(())
+    }
+    let auto = savepoint(conn, |tx| start_auto(app, tx, project));
+    let try_build = savepoint(conn, |tx| start_try(app, tx, project));
+    auto.and(try_build)
+}
+
+/// Runs `f` in a savepoint, which is rolled back if it fails.
+fn savepoint<F>(conn: &GenericConnection, f: F) -> BorsResult<()>
+    where F: FnOnce(&GenericConnection) -> BorsResult<()>,
+{
+    let tx = try!(conn.transaction());
+    try!(f(&tx));
+    tx.set_commit();
+    try!(tx.finish());
+    Ok(())
+}
+
+fn start_auto(app: &App,
+              conn: &GenericConnection,
+              project: &Project) -> BorsResult<()> {
+    if try!(PullRequest::find_pending(conn, project.id)).is_some() {
+        return Ok(())
+    }
+    let mut pr = match try!(PullRequest::next_in_queue(conn, project.id)) {
+        Some(pr) => pr,
+        None => return Ok(()),
+    };
+
+    info!("testing {}/{}#{}", project.repo_user, project.repo_name, pr.number);
+    let base = try!(branch_sha(app, project, &pr.base_ref));
+    try!(set_branch(app, project, AUTO_BRANCH, &base));
+
+    let message = format!("Auto merge of #{} - {}, r={}\n\n{}",
+                          pr.number,
+                          pr.head_ref,
+                          pr.approved_by.as_ref().unwrap(),
+                          pr.title);
+    let merged = merge(app, project, AUTO_BRANCH, &pr.head_commit, &message);
+    let sha = match merged {
+        Ok(Some(sha)) => sha,
+        Ok(None) => {
+            pr.status = Status::Error;
+            try!(pr.update(conn));
+            notify(set_status(app, project, &pr.head_commit, "error",
+                              "Merge conflict"));
+            notify(comment(app, project, pr.number,
+                           ":lock: Merge conflict, this pull request needs \
+                            a rebase"));
+            return Ok(())
+        }
+        Err(e) => {
+            if let BorsErrorKind::RateLimited(..) = *e.kind() {
+                return Err(e)
+            }
+            pr.status = Status::Error;
+            try!(pr.update(conn));
+            notify(set_status(app, project, &pr.head_commit, "error",
+                              "Merge failed"));
+            notify(comment(app, project, pr.number,
+                           &format!(":lock: Merge failed: {}", e)));
+            return Ok(())
+        }
+    };
+
+    pr.status = Status::Pending;
+    pr.merge_sha = Some(sha.clone());
+    try!(pr.update(conn));
+    notify(set_status(app, project, &pr.head_commit, "pending",
+                      &format!("Testing merge {}", sha)));
+    notify(comment(app, project, pr.number,
+                   &format!(":hourglass: Testing commit {} with merge {}...",
+                            pr.head_commit, sha)));
+    Ok(())
+}
+
+fn start_try(app: &App,
+             conn: &GenericConnection,
+             project: &Project) -> BorsResult<()> {
+    if try!(PullRequest::find_pending_try(conn, project.id)).is_some() {
+        return Ok(())
+    }
+    let mut pr = match try!(PullRequest::next_try(conn, project.id)) {
+        Some(pr) => pr,
+        None => return Ok(()),
+    };
+
+    info!("trying {}/{}#{}", project.repo_user, project.repo_name, pr.number);
+    let base = try!(branch_sha(app, project, &pr.base_ref));
+    try!(set_branch(app, project, TRY_BRANCH, &base));
+
+    let message = format!("Try #{} - {}\n\n{}",
+                          pr.number,
+                          pr.head_ref,
+                          pr.title);
+    pr.try_requested = false;
+    let merged = merge(app, project, TRY_BRANCH, &pr.head_commit, &message);
+    let sha = match merged {
+        Ok(Some(sha)) => sha,
+        Ok(None) => {
+            pr.try_status = Status::Error;
+            try!(pr.update(conn));
+            notify(comment(app, project, pr.number,
+                           ":lock: Merge conflict, this pull request needs \
+                            a rebase"));
+            return Ok(())
+        }
+        Err(e) => {
+            if let BorsErrorKind::RateLimited(..) = *e.kind() {
+                return Err(e)
+            }
+            pr.try_status = Status::Error;
+            try!(pr.update(conn));
+            notify(comment(app, project, pr.number,
+                           &format!(":lock: Merge failed: {}", e)));
+            return Ok(())
+        }
+    };
+
+    pr.try_status = Status::Pending;
+    pr.try_sha = Some(sha.clone());
+    try!(pr.update(conn));
+    notify(comment(app, project, pr.number,
+                   &format!(":hourglass: Trying commit {} with merge {}...",
+                            pr.head_commit, sha)));
+    Ok(())
+}
+
+/// Records a CI result for the merge commit `sha` on `branch`, finishing off
+/// the build once all providers have reported in.
+pub fn build_finished(app: &App,
+                      conn: &GenericConnection,
+                      project: &Project,
+                      provider: Provider,
+                      branch: &str,
+                      sha: &str,
+                      status: Status,
+                      url: &str) -> BorsResult<()> {
+    if branch == TRY_BRANCH {
+        return try_finished(app, conn, project, provider, sha, status, url)
+    }
+    if branch != AUTO_BRANCH {
+        return Ok(())
+    }
+
+    let mut pr = match try!(PullRequest::find_by_merge_sha(conn, project.id,
+                                                           sha)) {
+        Some(pr) => pr,
+        None => {
+            debug!("no pull request for {}", sha);
+            return Ok(())
+        }
+    };
+    try!(Build::record(conn, pr.id, provider, sha, status, url));
+
+    // Results for a build we're no longer waiting on, e.g. one which has
+    // already failed on another provider.
+    if pr.status != Status::Pending {
+        return Ok(())
+    }
+    // `r-` and closing the pull request already stop the build, but never
+    // land something which isn't open and approved anymore.
+    let approved_by = match pr.approved_by {
+        Some(ref reviewer) if pr.open => reviewer.clone(),
+        _ => {
+            info!("not landing #{}, it's closed or no longer approved",
+                  pr.number);
+            return Ok(())
+        }
+    };
+
+    let builds = try!(Build::find_by_sha(conn, pr.id, sha));
+    let overall = overall_status(project, &builds);
+    match overall {
+        Status::Success => {}
+        Status::Failure | Status::Error => {
+            pr.status = overall;
+            try!(pr.update(conn));
+            let state = if overall == Status::Failure {"failure"} else {"error"};
+            notify(set_status(app, project, &pr.head_commit, state,
+                              "Test failed"));
+            notify(comment(app, project, pr.number,
+                           &format!(":broken_heart: Test failed - {}", url)));
+            return Ok(())
+        }
+        _ => return Ok(()),
+    }
+
+    // Record the success before moving the base branch, so that once it has
+    // moved nothing failing afterwards can roll that back and have the build
+    // land again.
+    let landed = try!(conn.transaction());
+    pr.status = Status::Success;
+    try!(pr.update(&landed));
+    let rolled_up = try!(rollup_landed(&landed, &pr));
+
+    let url = format!("/repos/{}/{}/git/refs/heads/{}", project.repo_user,
+                      project.repo_name, pr.base_ref);
+    let update = github::UpdateRef {
+        sha: sha.to_string(),
+        force: false,
+    };
+    let res = http::github_patch::<github::GitRef, _>(
+        app,
+        &url,
+        &project.github_access_token,
+        &update);
+    if let Err(e) = res {
+        drop(landed);
+        pr.status = Status::Error;
+        try!(pr.update(conn));
+        notify(set_status(app, project, &pr.head_commit, "error",
+                          &format!("Failed to fast-forward {}", pr.base_ref)));
+        notify(comment(app, project, pr.number,
+                       &format!(":eyes: Failed to fast-forward {}: {}",
+                                pr.base_ref, e)));
+        return Ok(())
+    }
+    landed.set_commit();
+    try!(landed.finish());
+
+    notify(set_status(app, project, &pr.head_commit, "success",
+                      "Test successful"));
+    notify(comment(app, project, pr.number,
+                   &format!(":sunny: Test successful - approved by {}\n\
+                             Pushing {} to {}...",
+                            approved_by,
+                            sha,
+                            pr.base_ref)));
+    if !rolled_up.is_empty() {
+        for included in rolled_up.iter() {
+            notify(comment(app, project, included.number,
+                           &format!(":sunny: Merged in rollup #{}",
+                                    pr.number)));
+        }
+        notify(delete_branch(app, project, &pr.head_ref));
+    }
+    Ok(())
+}
+
+/// Merges all approved pull requests marked `rollup` into a new branch and
+/// opens a pull request for it.
+///
+/// Pull requests which fail to merge are left in the queue. Returns `None` if
+/// there was nothing to roll up.
+pub fn rollup(app: &App,
+              conn: &GenericConnection,
+              project: &Project) -> BorsResult<Option<PullRequest>> {
+    try!(project.lock(conn));
+
+    let candidates = try!(PullRequest::rollup_candidates(conn, project.id));
+    let base_ref = match candidates.first() {
+        Some(pr) => pr.base_ref.clone(),
+        None => return Ok(None),
+    };
+    let branch = format!("{}{}", ROLLUP_BRANCH_PREFIX,
+                         thread_rng().gen_ascii_chars().take(8)
+                                     .collect::<String>());
+    let base = try!(branch_sha(app, project, &base_ref));
+    try!(set_branch(app, project, &branch, &base));
+
+    let mut merged = Vec::new();
+    for pr in candidates.into_iter().filter(|pr| pr.base_ref == base_ref) {
+        let message = format!("Rollup merge of #{} - {}, r={}\n\n{}",
+                              pr.number,
+                              pr.head_ref,
+                              pr.approved_by.as_ref().unwrap(),
+                              pr.title);
+        match merge(app, project, &branch, &pr.head_commit, &message) {
+            Ok(Some(_)) => merged.push(pr),
+            Ok(None) => info!("#{} conflicts with the rollup", pr.number),
+            Err(e) => info!("failed to roll up #{}: {}", pr.number, e),
+        }
+    }
+    if merged.is_empty() {
+        try!(delete_branch(app, project, &branch));
+        return Ok(None)
+    }
+
+    let mut body = format!("Successful merges:\n\n");
+    for pr in merged.iter() {
+        body.push_str(&format!(" - #{} ({})\n", pr.number, pr.title));
+    }
+    let new = github::CreatePullRequest {
+        title: format!("Rollup of {} pull requests", merged.len()),
+        head: branch.clone(),
+        base: base_ref,
+        body: body,
+    };
+    let url = format!("/repos/{}/{}/pulls", project.repo_user,
+                      project.repo_name);
+    let res = http::github_post::<github::PullRequest, _>(
+        app,
+        &url,
+        &project.github_access_token,
+        &new);
+    let created = match res {
+        Ok(created) => created,
+        Err(e) => {
+            notify(delete_branch(app, project, &branch));
+            return Err(e)
+        }
+    };
+    let rollup = try!(PullRequest::insert(conn, project.id, &created));
+
+    for mut pr in merged {
+        pr.rollup_id = Some(rollup.id);
+        try!(pr.update(conn));
+        notify(comment(app, project, pr.number,
+                       &format!(":pushpin: Included in rollup #{}",
+                                rollup.number)));
+    }
+    Ok(Some(rollup))
+}
+
+/// Marks everything included in `rollup` as merged, if it was a rollup, and
+/// returns what it included.
+fn rollup_landed(conn: &GenericConnection,
+                 rollup: &PullRequest) -> BorsResult<Vec<PullRequest>> {
+    let mut prs = try!(PullRequest::find_by_rollup(conn, rollup.id));
+    for pr in prs.iter_mut() {
+        pr.status = Status::Success;
+        try!(pr.update(conn));
+    }
+    Ok(prs)
+}
+
+fn try_finished(app: &App,
+                conn: &GenericConnection,
+                project: &Project,
+                provider: Provider,
+                sha: &str,
+                status: Status,
+                url: &str) -> BorsResult<()> {
+    let mut pr = match try!(PullRequest::find_by_try_sha(conn, project.id,
+                                                         sha)) {
+        Some(pr) => pr,
+        None => {
+            debug!("no pull request for try {}", sha);
+            return Ok(())
+        }
+    };
+    try!(Build::record(conn, pr.id, provider, sha, status, url));
+    if pr.try_status != Status::Pending {
+        return Ok(())
+    }
+
+    let builds = try!(Build::find_by_sha(conn, pr.id, sha));
+    let overall = overall_status(project, &builds);
+    let msg = match overall {
+        Status::Success => {
+            format!(":sunny: Try build successful - {}", summary(&builds))
+        }
+        Status::Failure | Status::Error => {
+            format!(":broken_heart: Try build failed - {}", url)
+        }
+        _ => return Ok(()),
+    };
+    pr.try_status = overall;
+    try!(pr.update(conn));
+    notify(comment(app, project, pr.number, &msg));
+    Ok(())
+}
+
+/// Links to each provider's build, e.g. for a comment.
+fn summary(builds: &[Build]) -> String {
+    builds.iter().filter_map(|b| {
+        b.url.as_ref().map(|url| format!("[{:?}]({})", b.provider_id, url))
+    }).collect::<Vec<_>>().join(", ")
+}
+
+/// The CI providers which must pass before anything is merged.
+pub fn required_providers(project: &Project) -> Vec<Provider> {
+    let mut providers = Vec::new();
+    if project.travis_access_token.is_some() {
+        providers.push(Provider::Travis);
+    }
+    if project.appveyor_token.is_some() {
+        providers.push(Provider::AppVeyor);
+    }
+    providers
+}
+
+/// Combines the results of each provider into one: any failure fails the
+/// build, and it has only succeeded once every provider has succeeded.
+fn overall_status(project: &Project, builds: &[Build]) -> Status {
+    let mut status = Status::Success;
+    for provider in required_providers(project) {
+        match builds.iter().find(|b| b.provider_id == provider) {
+            Some(b) if b.status == Status::Success => {}
+            Some(b) if b.status == Status::Failure ||
+                       b.status == Status::Error => return b.status,
+            _ => status = Status::Pending,
+        }
+    }
+    status
+}
+
+fn branch_sha(app: &App,
+              project: &Project,
+              branch: &str) -> BorsResult<String> {
+    let url = format!("/repos/{}/{}/git/refs/heads/{}", project.repo_user,
+                      project.repo_name, branch);
+    let r: github::GitRef = try!(http::github_get(
+        app,
+        &url,
+        &project.github_access_token));
+    Ok(r.object.sha)
+}
+
+/// Force-pushes `branch` to `sha`, creating it if it doesn't exist yet.
+fn set_branch(app: &App,
+              project: &Project,
+              branch: &str,
+              sha: &str) -> BorsResult<()> {
+    let url = format!("/repos/{}/{}/git/refs/heads/{}", project.repo_user,
+                      project.repo_name, branch);
+    let update = github::UpdateRef {
+        sha: sha.to_string(),
+        force: true,
+    };
+    let res = http::github_patch::<github::GitRef, _>(
+        app,
+        &url,
+        &project.github_access_token,
+        &update);
+    match res {
+        Ok(..) => return Ok(()),
+        // GitHub answers a 422 for refs which don't exist
+        Err(e) => {
+            let missing = match *e.kind() {
+                BorsErrorKind::NotFound |
+                BorsErrorKind::ApiError(422, _) => true,
+                _ => false,
+            };
+            if !missing {
+                return Err(e)
+            }
+        }
+    }
+
+    let url = format!("/repos/{}/{}/git/refs", project.repo_user,
+                      project.repo_name);
+    let create = github::CreateRef {
+        ref_: format!("refs/heads/{}", branch),
+        sha: sha.to_string(),
+    };
+    let r: github::GitRef = try!(http::github_post(
+        app,
+        &url,
+        &project.github_access_token,
+        &create));
+    drop(r);
+    Ok(())
+}
+
+fn delete_branch(app: &App,
+                 project: &Project,
+                 branch: &str) -> BorsResult<()> {
+    let url = format!("/repos/{}/{}/git/refs/heads/{}", project.repo_user,
+                      project.repo_name, branch);
+    http::github_delete(app, &url, &project.github_access_token)
+}
+
+/// Merges `head` into `base`, returning the sha of the merge commit or `None`
+/// if they conflict.
+fn merge(app: &App,
+         project: &Project,
+         base: &str,
+         head: &str,
+         message: &str) -> BorsResult<Option<String>> {
+    let url = format!("/repos/{}/{}/merges", project.repo_user,
+                      project.repo_name);
+    let merge = github::CreateMerge {
+        base: base.to_string(),
+        head: head.to_string(),
+        commit_message: message.to_string(),
+    };
+    let res = http::github_post::<github::Commit, _>(
+        app,
+        &url,
+        &project.github_access_token,
+        &merge);
+    match res {
+        Ok(commit) => Ok(Some(commit.sha)),
+        Err(e) => {
+            if let BorsErrorKind::ApiError(409, _) = *e.kind() {
+                return Ok(None)
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Logs a failed notification, such as a comment or a commit status, instead
+/// of failing.
+///
+/// Notifications are sent after changes which a failed notification mustn't
+/// roll back: after a change on GitHub that would have the queue make it
+/// again, and after applying commands it would lose them.
+pub fn notify(res: BorsResult<()>) {
+    if let Err(e) = res {
+        warn!("failed to notify github: {}", e);
+    }
+}
+
+pub fn comment(app: &App,
+               project: &Project,
+               number: i32,
+               body: &str) -> BorsResult<()> {
+    let url = format!("/repos/{}/{}/issues/{}/comments", project.repo_user,
+                      project.repo_name, number);
+    let comment = github::CreateComment { body: body.to_string() };
+    let c: github::Comment = try!(http::github_post(
+        app,
+        &url,
+        &project.github_access_token,
+        &comment));
+    drop(c);
+    Ok(())
+}
+
+/// Sets bors's commit status on `sha`, which shows up on the pull request
+/// page.
+pub fn set_status(app: &App,
+                  project: &Project,
+                  sha: &str,
+                  state: &str,
+                  description: &str) -> BorsResult<()> {
+    let url = format!("/repos/{}/{}/statuses/{}", project.repo_user,
+                      project.repo_name, sha);
+    let status = github::CreateStatus {
+        state: state.to_string(),
+        target_url: format!("{}/repos/{}/{}", app.config.host,
+                            project.repo_user, project.repo_name),
+        description: description.to_string(),
+        context: STATUS_CONTEXT.to_string(),
+    };
+    let s: github::CommitStatus = try!(http::github_post(
+        app,
+        &url,
+        &project.github_access_token,
+        &status));
+    drop(s);
+    Ok(())
+}
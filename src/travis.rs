@@ -1,3 +1,20 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use openssl::crypto::hash::{self, Type};
+use openssl::crypto::pkey::PKey;
+
+use errors::*;
+use http::{self, ApiClient};
+
+/// How long a public key is used before it's fetched again.
+const KEY_TTL_SECS: u64 = 60 * 60;
+
+/// Keys are never refetched more often than this, even if signatures fail to
+/// verify, so bogus requests can't make us hammer Travis.
+const KEY_MIN_REFRESH_SECS: u64 = 60;
+
 #[derive(RustcDecodable)]
 pub struct GetRepository {
     pub repo: Repository,
@@ -37,3 +54,118 @@ pub struct Notifications {
 pub struct Webhook {
     pub public_key: String,
 }
+
+/// The `payload` of a webhook notification.
+#[derive(RustcDecodable)]
+pub struct Payload {
+    pub id: i32,
+    pub number: String,
+    pub state: String,
+    pub branch: String,
+    pub commit: String,
+    pub build_url: String,
+}
+
+/// Cache of the public keys Travis signs its webhooks with.
+///
+/// Each Travis installation (travis-ci.org, travis-ci.com, or a self-hosted
+/// Travis Enterprise) signs with its own key, and notifications don't say
+/// which installation they came from, so a signature is accepted if it
+/// verifies with the key of any configured endpoint.
+pub struct KeyCache {
+    endpoints: Vec<String>,
+    ttl: Duration,
+    min_refresh: Duration,
+    keys: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl KeyCache {
+    pub fn new(endpoints: Vec<String>) -> KeyCache {
+        KeyCache::with_ttl(endpoints,
+                           Duration::from_secs(KEY_TTL_SECS),
+                           Duration::from_secs(KEY_MIN_REFRESH_SECS))
+    }
+
+    /// Like `new`, but keys are used for `ttl` and refetched after a failed
+    /// verification at most every `min_refresh`.
+    pub fn with_ttl(endpoints: Vec<String>,
+                    ttl: Duration,
+                    min_refresh: Duration) -> KeyCache {
+        KeyCache {
+            endpoints: endpoints,
+            ttl: ttl,
+            min_refresh: min_refresh,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Verifies that `signature` is a signature of `payload` by one of our
+    /// Travis endpoints.
+    ///
+    /// If verification fails with the cached keys they're refetched once in
+    /// case Travis has rotated them.
+    pub fn verify(&self,
+                  client: &ApiClient,
+                  payload: &[u8],
+                  signature: &[u8]) -> BorsResult<()> {
+        let digest = try!(hash::hash(Type::SHA1, payload));
+        if self.verify_with_keys(client, &digest, signature, false) ||
+           self.verify_with_keys(client, &digest, signature, true) {
+            Ok(())
+        } else {
+            Err("invalid signature".into())
+        }
+    }
+
+    fn verify_with_keys(&self,
+                        client: &ApiClient,
+                        digest: &[u8],
+                        signature: &[u8],
+                        refresh: bool) -> bool {
+        self.endpoints.iter().any(|endpoint| {
+            let key = match self.key(client, endpoint, refresh) {
+                Ok(key) => key,
+                Err(e) => {
+                    warn!("failed to get travis key for {}: {}", endpoint, e);
+                    return false
+                }
+            };
+            let res = PKey::public_key_from_pem(key.as_bytes()).and_then(|key| {
+                key.get_rsa()
+            });
+            match res {
+                Ok(rsa) => rsa.verify(Type::SHA1, digest, signature).is_ok(),
+                Err(e) => {
+                    warn!("invalid travis key for {}: {}", endpoint, e);
+                    false
+                }
+            }
+        })
+    }
+
+    fn key(&self,
+           client: &ApiClient,
+           endpoint: &str,
+           refresh: bool) -> BorsResult<String> {
+        {
+            let keys = self.keys.lock().unwrap();
+            if let Some(&(ref key, fetched)) = keys.get(endpoint) {
+                let age = fetched.elapsed();
+                if age < self.min_refresh || (!refresh && age < self.ttl) {
+                    return Ok(key.clone())
+                }
+            }
+        }
+
+        // Fetched without holding the lock so a slow Travis doesn't hold up
+        // webhooks which could be verified with the cached keys.
+        let url = format!("{}/config", endpoint);
+        let config: GetConfig = try!(http::get(client, &url, &[]).chain_err(|| {
+            format!("failed to get travis config from {}", endpoint)
+        }));
+        let key = config.config.notifications.webhook.public_key;
+        let mut keys = self.keys.lock().unwrap();
+        keys.insert(endpoint.to_string(), (key.clone(), Instant::now()));
+        Ok(key)
+    }
+}
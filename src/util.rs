@@ -34,6 +34,19 @@ pub fn html(text: &str) -> Response {
     }
 }
 
+/// A plain text response with an error `status`.
+pub fn error(code: u32, reason: &'static str, msg: &str) -> Response {
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(),
+                   vec!["text/plain; charset=utf-8".to_string()]);
+    headers.insert("Content-Length".to_string(), vec![msg.len().to_string()]);
+    Response {
+        status: (code, reason),
+        headers: headers,
+        body: Box::new(Cursor::new(msg.to_string().into_bytes())),
+    }
+}
+
 pub fn redirect(url: &str) -> Response {
     let mut headers = HashMap::new();
     headers.insert("Location".to_string(), vec![url.to_string()]);
@@ -0,0 +1,364 @@
+//! The background worker which processes the events stored by the webhook
+//! handlers.
+//!
+//! Events are claimed one at a time from the `events` table and handed off to
+//! a handler for the provider they came from. Each event is handled inside of
+//! a savepoint so a failing handler leaves no partial writes behind, and the
+//! event is then marked as either processed or failed.
+
+use std::cmp;
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pg::GenericConnection;
+use rustc_serialize::json;
+
+use app::App;
+use appveyor;
+use commands;
+use errors::*;
+use github;
+use hooks;
+use http;
+use models::*;
+use queue;
+use travis;
+
+/// How long to sleep when there are no events left to process.
+const POLL_INTERVAL_SECS: u64 = 5;
+
+/// How often every project's webhook is checked on.
+const WEBHOOK_CHECK_INTERVAL_SECS: u64 = 60 * 60;
+
+/// Processes events forever.
+pub fn run(app: &App) {
+    let mut last_webhook_check: Option<Instant> = None;
+    let interval = Duration::from_secs(WEBHOOK_CHECK_INTERVAL_SECS);
+    loop {
+        // Checked whether or not there are events waiting, a steady stream
+        // of them mustn't keep broken webhooks from being noticed.
+        if last_webhook_check.map(|t| t.elapsed() >= interval)
+                             .unwrap_or(true) {
+            check_webhooks(app);
+            last_webhook_check = Some(Instant::now());
+        }
+        match process_next(app) {
+            Ok(true) => {}
+            Ok(false) => {
+                process_queues(app);
+                thread::sleep(Duration::from_secs(POLL_INTERVAL_SECS));
+            }
+            Err(e) => {
+                log_error(&e);
+                thread::sleep(Duration::from_secs(POLL_INTERVAL_SECS));
+            }
+        }
+    }
+}
+
+/// Claims and processes the next unprocessed event.
+///
+/// Returns `Ok(false)` if there was nothing to do.
+pub fn process_next(app: &App) -> BorsResult<bool> {
+    let conn = try!(app.database.get().chain_err(|| {
+        "failed to get a database connection"
+    }));
+    let tx = try!(conn.transaction());
+    let event = match try!(Event::claim_next(&tx)) {
+        Some(event) => event,
+        None => return Ok(false),
+    };
+
+    let savepoint = try!(tx.transaction());
+    // Either the state to leave the event in or how long to put it off for.
+    let state = match handle(app, &savepoint, &event) {
+        Ok(()) => {
+            savepoint.set_commit();
+            Ok(State::Processed)
+        }
+        Err(e) => {
+            // Put the event off until the rate limit has reset, letting
+            // other events be processed in the meantime.
+            if let BorsErrorKind::RateLimited(secs) = *e.kind() {
+                info!("event {} hit a rate limit, retrying in {}s", event.id,
+                      secs);
+                Err(cmp::max(secs, POLL_INTERVAL_SECS))
+            } else {
+                error!("failed to process event {}", event.id);
+                log_error(&e);
+                Ok(State::Failed)
+            }
+        }
+    };
+    try!(savepoint.finish());
+
+    match state {
+        Ok(state) => try!(event.set_state(&tx, state)),
+        Err(secs) => try!(event.postpone(&tx, secs)),
+    }
+    tx.set_commit();
+    try!(tx.finish());
+
+    // Now that the event is recorded as processed, see if it unblocked the
+    // project's merge queue.
+    if let Some(id) = event.project_id {
+        let tx = try!(conn.transaction());
+        let project = try!(Project::find(&tx, &app.encryption_key, id));
+        let res = queue::process(app, &tx, &project);
+        tx.set_commit();
+        try!(tx.finish());
+        try!(res);
+    }
+    Ok(true)
+}
+
+/// Gives every project's merge queue a chance to make progress, for example
+/// after a previous attempt failed on a GitHub hiccup.
+fn process_queues(app: &App) {
+    let res = app.database.get().chain_err(|| {
+        "failed to get a database connection"
+    }).and_then(|conn| {
+        for project in try!(Project::all(&*conn, &app.encryption_key)) {
+            let tx = try!(conn.transaction());
+            if let Err(e) = queue::process(app, &tx, &project) {
+                error!("failed to process queue of {}/{}",
+                       project.repo_user, project.repo_name);
+                log_error(&e);
+            }
+            tx.set_commit();
+            try!(tx.finish());
+        }
+        Ok(())
+    });
+    if let Err(e) = res {
+        log_error(&e);
+    }
+}
+
+/// Makes sure every project's webhook is still there and working, see
+/// `hooks::check`.
+fn check_webhooks(app: &App) {
+    let res = app.database.get().chain_err(|| {
+        "failed to get a database connection"
+    }).and_then(|conn| {
+        for project in try!(Project::all(&*conn, &app.encryption_key)) {
+            // A failed check is still stored as the webhook's status.
+            let tx = try!(conn.transaction());
+            if let Err(e) = hooks::check(app, &tx, &project) {
+                error!("failed to check the webhook of {}/{}",
+                       project.repo_user, project.repo_name);
+                log_error(&e);
+            }
+            tx.set_commit();
+            try!(tx.finish());
+        }
+        Ok(())
+    });
+    if let Err(e) = res {
+        log_error(&e);
+    }
+}
+
+/// Applies `event` to its project.
+pub fn handle(app: &App,
+              conn: &GenericConnection,
+              event: &Event) -> BorsResult<()> {
+    let project = match event.project_id {
+        Some(id) => try!(Project::find(conn, &app.encryption_key, id)),
+        None => {
+            info!("skipping event {} without a project", event.id);
+            return Ok(())
+        }
+    };
+    // Handle one event per project at a time so that two deliveries of the
+    // same build result can't both see the pull request as still pending.
+    try!(project.lock(conn));
+
+    match event.provider_id {
+        Provider::GitHub => github_event(app, conn, &project, event),
+        Provider::Travis => travis_event(app, conn, &project, event),
+        Provider::AppVeyor => appveyor_event(app, conn, &project, event),
+    }
+}
+
+fn github_event(app: &App,
+                conn: &GenericConnection,
+                project: &Project,
+                event: &Event) -> BorsResult<()> {
+    info!("github event {} `{}` for {}/{}", event.id, event.provider_event,
+          project.repo_user, project.repo_name);
+    match try!(github::Payload::from_event(event)) {
+        github::Payload::PullRequest(e) => {
+            let pr = try!(PullRequest::find_by_number(conn, project.id,
+                                                      e.number));
+            match pr {
+                Some(mut pr) => {
+                    pr.sync(&e.pull_request);
+                    try!(pr.update(conn));
+                    // A rollup closed without landing gives its pull requests
+                    // back to the queue.
+                    if !pr.open && pr.status != Status::Success {
+                        try!(PullRequest::release_rollup(conn, pr.id));
+                    }
+                    Ok(())
+                }
+                None => {
+                    PullRequest::insert(conn, project.id, &e.pull_request)
+                        .map(|_| ())
+                }
+            }
+        }
+        github::Payload::IssueComment(e) => {
+            // Only new comments on pull requests can carry commands
+            if e.action != "created" || e.issue.pull_request.is_none() {
+                return Ok(())
+            }
+            run_commands(app, conn, project, e.issue.number,
+                         &e.comment.user.login, &e.comment.body)
+        }
+        github::Payload::PullRequestReviewComment(e) => {
+            if e.action != "created" {
+                return Ok(())
+            }
+            run_commands(app, conn, project, e.pull_request.number,
+                         &e.comment.user.login, &e.comment.body)
+        }
+        github::Payload::PullRequestReview(e) => {
+            match e.review.body {
+                Some(ref body) if e.action == "submitted" => {
+                    run_commands(app, conn, project, e.pull_request.number,
+                                 &e.review.user.login, body)
+                }
+                _ => Ok(()),
+            }
+        }
+        github::Payload::Repository(e) => {
+            if e.action != "renamed" && e.action != "transferred" {
+                return Ok(())
+            }
+            let repo = e.repository;
+            info!("{}/{} is now {}/{}", project.repo_user, project.repo_name,
+                  repo.owner.login, repo.name);
+            try!(project.rename(conn, &repo.owner.login, &repo.name));
+
+            // Point the webhook at the new name, if this fails the periodic
+            // check will do it later.
+            let project = try!(Project::find(conn, &app.encryption_key,
+                                             project.id));
+            if let Err(e) = hooks::check(app, conn, &project) {
+                warn!("failed to update the webhook of {}/{}: {}",
+                      project.repo_user, project.repo_name, e);
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies all commands in `body`, written by `user`, to pull request
+/// `number`.
+fn run_commands(app: &App,
+                conn: &GenericConnection,
+                project: &Project,
+                number: i32,
+                user: &str,
+                body: &str) -> BorsResult<()> {
+    let commands = commands::parse(body);
+    if commands.is_empty() {
+        return Ok(())
+    }
+
+    let mut pr = try!(pull_request(app, conn, project, number));
+    let permission = try!(Reviewer::permission(conn, project.id, user));
+    let mut denied = false;
+    for command in commands.iter() {
+        let delegated = pr.delegate.as_ref().map(|d| d == user)
+                          .unwrap_or(false);
+        if !command.permitted(permission, delegated) {
+            info!("{}/{}#{}: {:?} from {} denied", project.repo_user,
+                  project.repo_name, number, command, user);
+            denied = true;
+            continue
+        }
+        info!("{}/{}#{}: {:?} from {}", project.repo_user, project.repo_name,
+              number, command, user);
+        command.apply(&mut pr, user);
+    }
+    try!(pr.update(conn));
+
+    if denied {
+        let msg = format!(":key: @{}, you don't have permission to do that \
+                           on this repository; ask one of its reviewers.",
+                          user);
+        queue::notify(queue::comment(app, project, number, &msg));
+    }
+    Ok(())
+}
+
+/// Loads our record of pull request `number`, fetching it from GitHub if this
+/// is the first we've heard of it.
+fn pull_request(app: &App,
+                conn: &GenericConnection,
+                project: &Project,
+                number: i32) -> BorsResult<PullRequest> {
+    if let Some(pr) = try!(PullRequest::find_by_number(conn, project.id,
+                                                       number)) {
+        return Ok(pr)
+    }
+    let url = format!("/repos/{}/{}/pulls/{}", project.repo_user,
+                      project.repo_name, number);
+    let pr: github::PullRequest = try!(http::github_get(
+        app,
+        &url,
+        &project.github_access_token));
+    PullRequest::insert(conn, project.id, &pr)
+}
+
+fn travis_event(app: &App,
+                conn: &GenericConnection,
+                project: &Project,
+                event: &Event) -> BorsResult<()> {
+    info!("travis event {} for {}/{}", event.id,
+          project.repo_user, project.repo_name);
+    let payload: travis::Payload = try!(json::decode(&event.event));
+    let status = match &payload.state[..] {
+        "passed" => Status::Success,
+        "failed" => Status::Failure,
+        "errored" | "canceled" => Status::Error,
+        _ => Status::Pending,
+    };
+    queue::build_finished(app, conn, project, Provider::Travis,
+                          &payload.branch, &payload.commit, status,
+                          &payload.build_url)
+}
+
+fn appveyor_event(app: &App,
+                  conn: &GenericConnection,
+                  project: &Project,
+                  event: &Event) -> BorsResult<()> {
+    info!("appveyor event {} for {}/{}", event.id,
+          project.repo_user, project.repo_name);
+    // The webhook stores the build as fetched from AppVeyor rather than the
+    // notification, which anyone could have sent.
+    let actual: appveyor::GetBuild = try!(json::decode(&event.event));
+    let build = &actual.build;
+    let status = match &build.status.to_lowercase()[..] {
+        "success" => Status::Success,
+        "failed" => Status::Failure,
+        "cancelled" => Status::Error,
+        _ => Status::Pending,
+    };
+    let url = appveyor::build_url(app, &actual.project, build);
+    queue::build_finished(app, conn, project, Provider::AppVeyor,
+                          &build.branch, &build.commitId, status, &url)
+}
+
+fn log_error(err: &BorsError) {
+    error!("error: {}", err);
+    let mut cur = err.cause();
+    while let Some(e) = cur {
+        error!("error: {}", e);
+        cur = e.cause();
+    }
+}
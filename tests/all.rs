@@ -0,0 +1,322 @@
+//! Tests which drive requests through the router with the `Env::Test`
+//! middleware stack.
+//!
+//! These need a migrated database in `TEST_DATABASE_URL`. Each request runs
+//! in a transaction which is never committed, so tests leave nothing behind.
+//! Requests to GitHub, Travis and AppVeyor are answered by a `Fake` client.
+
+extern crate bors2;
+extern crate conduit;
+extern crate conduit_middleware;
+extern crate conduit_test;
+extern crate openssl;
+extern crate postgres;
+extern crate rustc_serialize;
+
+use std::env;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use bors2::app::App;
+use bors2::db::{self, RequestTransaction};
+use bors2::errors::*;
+use bors2::http::{self, ApiClient};
+use bors2::models::Project;
+use conduit::{Handler, Method, Request, Response};
+use conduit_middleware::MiddlewareBuilder;
+use conduit_test::MockRequest;
+use postgres::GenericConnection;
+
+mod project;
+mod queue;
+mod session;
+mod travis;
+mod webhook;
+
+/// `bors/test`, as seen by a user with push access to it.
+const REPO: &'static str = r#"{
+    "id": 42,
+    "name": "test",
+    "permissions": {"admin": true, "push": true, "pull": true}
+}"#;
+
+/// An `ApiClient` which answers with canned responses and records every
+/// request made.
+#[derive(Clone)]
+struct Fake {
+    state: Arc<Mutex<FakeState>>,
+}
+
+struct FakeState {
+    responses: Vec<(http::Method, String, u32, Vec<(String, String)>, String)>,
+    requests: Vec<(http::Method, String, String)>,
+}
+
+impl Fake {
+    fn new() -> Fake {
+        Fake {
+            state: Arc::new(Mutex::new(FakeState {
+                responses: Vec::new(),
+                requests: Vec::new(),
+            })),
+        }
+    }
+
+    /// Answers all `method` requests to `url` with `status` and `body`,
+    /// replacing any previous answer. A `url` ending in `*` answers every url
+    /// starting with the rest of it which has no answer of its own.
+    fn respond(&self, method: http::Method, url: &str, status: u32, body: &str) {
+        self.respond_with_headers(method, url, status, &[], body)
+    }
+
+    /// Like `respond`, but also sends `headers` back.
+    fn respond_with_headers(&self,
+                            method: http::Method,
+                            url: &str,
+                            status: u32,
+                            headers: &[(&str, &str)],
+                            body: &str) {
+        let headers = headers.iter().map(|&(k, v)| {
+            (k.to_string(), v.to_string())
+        }).collect();
+        let mut state = self.state.lock().unwrap();
+        state.responses.retain(|r| r.0 != method || r.1 != url);
+        state.responses.push((method, url.to_string(), status, headers,
+                              body.to_string()));
+    }
+
+    /// The method, url and body of every request made so far.
+    fn requests(&self) -> Vec<(http::Method, String, String)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl ApiClient for Fake {
+    fn request(&self,
+               method: http::Method,
+               url: &str,
+               _headers: &[String],
+               body: Option<&[u8]>) -> BorsResult<http::Response> {
+        let mut state = self.state.lock().unwrap();
+        let body = body.map(|b| String::from_utf8_lossy(b).into_owned());
+        state.requests.push((method, url.to_string(),
+                             body.unwrap_or(String::new())));
+        let exact = state.responses.iter().find(|r| {
+            r.0 == method && r.1 == url
+        });
+        let found = exact.or_else(|| {
+            state.responses.iter().find(|r| {
+                r.0 == method && r.1.ends_with('*') &&
+                    url.starts_with(r.1.trim_right_matches('*'))
+            })
+        });
+        match found {
+            Some(r) => {
+                Ok(http::Response {
+                    status: r.2,
+                    headers: r.3.clone(),
+                    body: r.4.clone().into_bytes(),
+                })
+            }
+            None => Err(format!("unexpected request {:?} {}", method, url).into()),
+        }
+    }
+}
+
+fn app() -> (Arc<App>, MiddlewareBuilder) {
+    app_with(Fake::new())
+}
+
+fn app_with(fake: Fake) -> (Arc<App>, MiddlewareBuilder) {
+    let config = bors2::Config {
+        session_key: "test".to_string(),
+        gh_client_id: String::new(),
+        gh_client_secret: String::new(),
+        db_url: env::var("TEST_DATABASE_URL")
+                    .expect("TEST_DATABASE_URL must be set to run tests"),
+        env: bors2::Env::Test,
+        host: "http://localhost".to_string(),
+        travis_endpoints: Vec::new(),
+        encryption_key: "test".to_string(),
+        old_encryption_key: None,
+        github_url: "http://github.invalid".to_string(),
+        github_api_url: "http://api.github.invalid".to_string(),
+        travis_api_url: "http://api.travis.invalid".to_string(),
+        appveyor_api_url: "http://appveyor.invalid/api".to_string(),
+    };
+    let mut app = App::new(&config);
+    app.http = Box::new(fake);
+    let app = Arc::new(app);
+    let middleware = bors2::middleware(app.clone());
+    (app, middleware)
+}
+
+/// Creates a request whose database transaction is rolled back once the
+/// request is dropped.
+fn req(app: &Arc<App>, method: Method, path: &str) -> MockRequest {
+    let mut req = MockRequest::new(method, path);
+    req.mut_extensions().insert(db::Transaction::new(app.clone()));
+    req
+}
+
+/// The transaction of a request created with `req` or `signed_in`.
+fn tx(req: &Request) -> &GenericConnection {
+    req.tx().unwrap()
+}
+
+/// A browser session: its cookie and the token its forms send back.
+struct Session {
+    cookie: String,
+    csrf_token: String,
+}
+
+/// Logs in as `alice` through the GitHub OAuth flow.
+///
+/// Returns the database transaction the user was created in, to be handed to
+/// the next request with `signed_in`, and the session.
+fn sign_in(app: &Arc<App>,
+           middle: &MiddlewareBuilder,
+           fake: &Fake) -> (db::Transaction, Session) {
+    fake.respond(http::Method::Post,
+                 "http://github.invalid/login/oauth/access_token",
+                 200, r#"{"access_token": "user-token"}"#);
+    fake.respond(http::Method::Get, "http://api.github.invalid/user",
+                 200, r#"{"id": 1, "login": "alice"}"#);
+
+    let mut login = req(app, Method::Get, "/login");
+    let resp = call(middle, &mut login);
+    let location = resp.headers["Location"][0].clone();
+    let state = location.split("state=").nth(1).unwrap()
+                        .split('&').next().unwrap();
+
+    let mut authorize = MockRequest::new(Method::Get, "/authorize/github");
+    authorize.header("Cookie", &cookies(&resp));
+    authorize.mut_extensions().insert(login.mut_extensions()
+                                           .pop::<db::Transaction>()
+                                           .unwrap());
+    authorize.with_query(&format!("code=login-code&state={}", state));
+    let resp = call(middle, &mut authorize);
+    assert_eq!(resp.status.0, 302);
+    let tx = authorize.mut_extensions().pop::<db::Transaction>().unwrap();
+    session(middle, tx, &cookies(&resp))
+}
+
+/// Logs in as `alice`, who has push access to `bors/test`, like `sign_in`.
+fn maintainer() -> (Fake,
+                    Arc<App>,
+                    MiddlewareBuilder,
+                    db::Transaction,
+                    Session) {
+    let fake = Fake::new();
+    let (app, middle) = app_with(fake.clone());
+    let (tx, session) = sign_in(&app, &middle, &fake);
+    fake.respond(http::Method::Get, "http://api.github.invalid/repos/bors/test",
+                 200, REPO);
+    (fake, app, middle, tx, session)
+}
+
+/// Visits the front page with `cookie` to pick up the session's form token.
+fn session(middle: &MiddlewareBuilder,
+           tx: db::Transaction,
+           cookie: &str) -> (db::Transaction, Session) {
+    let mut page = MockRequest::new(Method::Get, "/");
+    page.header("Cookie", cookie);
+    page.mut_extensions().insert(tx);
+    let resp = call(middle, &mut page);
+    let cookie = cookies(&resp);
+    let body = body(resp);
+    let csrf_token = body.split("csrf_token=").nth(1).unwrap()
+                         .split(|c: char| c == '\'' || c == '"')
+                         .next().unwrap().to_string();
+    let tx = page.mut_extensions().pop::<db::Transaction>().unwrap();
+    (tx, Session { cookie: cookie, csrf_token: csrf_token })
+}
+
+/// Creates a request in the transaction and session returned by `sign_in`.
+///
+/// `POST`s send the session's form token along like our forms do.
+fn signed_in(tx: db::Transaction,
+             session: &Session,
+             method: Method,
+             path: &str) -> MockRequest {
+    let mut req = MockRequest::new(method, path);
+    req.header("Cookie", &session.cookie);
+    if method == Method::Post {
+        req.with_query(&format!("csrf_token={}", session.csrf_token));
+    }
+    req.mut_extensions().insert(tx);
+    req
+}
+
+/// The cookies set by `resp`, as a `Cookie` header.
+fn cookies(resp: &Response) -> String {
+    resp.headers.get("Set-Cookie").map(|cookies| {
+        cookies.iter()
+               .map(|c| c.split(';').next().unwrap())
+               .collect::<Vec<_>>()
+               .join("; ")
+    }).unwrap_or(String::new())
+}
+
+/// Registers `user/repo` with webhook 1. Every project gets its own
+/// repository id so concurrent tests don't wait on each other's inserts.
+fn project(app: &App, req: &Request, user: &str, repo: &str) -> Project {
+    static NEXT_REPO_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+    let repo_id = 1000 + NEXT_REPO_ID.fetch_add(1, Ordering::SeqCst) as i32;
+    let tx = req.tx().unwrap();
+    let project = Project::upsert(tx,
+                                  &app.encryption_key,
+                                  user,
+                                  repo,
+                                  repo_id,
+                                  "github-token",
+                                  "webhook-secret").unwrap();
+    project.set_github_webhook(tx, 1, "ok").unwrap();
+    Project::find(tx, &app.encryption_key, project.id).unwrap()
+}
+
+/// Pull request `number` of `bors/test` as GitHub describes it, in `state`
+/// and with the commit `head` as its head.
+fn pull_request(number: i32, state: &str, head: &str) -> String {
+    format!(r#"{{
+        "id": {number},
+        "number": {number},
+        "state": "{state}",
+        "title": "Change number {number}",
+        "user": {{"id": 2, "login": "alice"}},
+        "head": {{
+            "label": "alice:change-{number}",
+            "ref": "change-{number}",
+            "sha": "{head}"
+        }},
+        "base": {{
+            "label": "bors:master",
+            "ref": "master",
+            "sha": "1111111111111111111111111111111111111111"
+        }},
+        "mergeable": true,
+        "assignee": null
+    }}"#, number = number, state = state, head = head)
+}
+
+/// A `pull_request` webhook payload for `action` on pull request `number`,
+/// see `pull_request`.
+fn pull_request_event(action: &str, number: i32, head: &str) -> String {
+    let state = if action == "closed" {"closed"} else {"open"};
+    format!(r#"{{
+        "action": "{}",
+        "number": {},
+        "pull_request": {}
+    }}"#, action, number, pull_request(number, state, head))
+}
+
+fn body(mut resp: Response) -> String {
+    let mut s = String::new();
+    resp.body.read_to_string(&mut s).unwrap();
+    s
+}
+
+fn call(middle: &MiddlewareBuilder, req: &mut MockRequest) -> Response {
+    middle.call(req).unwrap()
+}
@@ -0,0 +1,498 @@
+use conduit::{Method, Request};
+use conduit_middleware::MiddlewareBuilder;
+use rustc_serialize::json;
+
+use bors2::db;
+use bors2::github;
+use bors2::http;
+use bors2::models::{Event, Permission, Project, Provider, PullRequest, Reviewer};
+use bors2::models::{State, Status};
+
+const READ_ONLY_REPO: &'static str = r#"{
+    "id": 42,
+    "name": "test",
+    "permissions": {"admin": false, "push": false, "pull": true}
+}"#;
+
+const HOOK: &'static str = r#"{
+    "id": 1,
+    "url": "http://api.github.invalid/repos/bors/test/hooks/1",
+    "name": "web",
+    "events": ["pull_request"],
+    "active": true,
+    "config": {},
+    "updated_at": "2016-12-17T00:00:00Z",
+    "created_at": "2016-12-17T00:00:00Z"
+}"#;
+
+/// Submits `bors/test` to be added like the form on the front page does,
+/// returning the OAuth state which GitHub hands back to `/authorize/github`.
+fn start_adding(middle: &MiddlewareBuilder,
+                tx: db::Transaction,
+                session: &::Session) -> (db::Transaction, ::Session, String) {
+    let mut req = ::signed_in(tx, session, Method::Post, "/repos");
+    req.with_body(b"repo=bors/test");
+    let resp = ::call(middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+    let location = resp.headers["Location"][0].clone();
+    let state = location.split("state=").nth(1).unwrap()
+                        .split('&').next().unwrap().to_string();
+    let session = ::Session {
+        cookie: ::cookies(&resp),
+        csrf_token: session.csrf_token.clone(),
+    };
+    let tx = req.mut_extensions().pop::<db::Transaction>().unwrap();
+    (tx, session, state)
+}
+
+#[test]
+fn add_project() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Post,
+                 "http://github.invalid/login/oauth/access_token",
+                 200, r#"{"access_token": "project-token"}"#);
+    fake.respond(http::Method::Get,
+                 "http://api.github.invalid/repos/bors/test/hooks",
+                 200, "[]");
+    fake.respond(http::Method::Post,
+                 "http://api.github.invalid/repos/bors/test/hooks",
+                 201, HOOK);
+
+    let (tx, session, state) = start_adding(&middle, tx, &session);
+    let mut req = ::signed_in(tx, &session, Method::Get, "/authorize/github");
+    req.with_query(&format!("code=repo-code&state={}", state));
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let project = Project::find_by_name(::tx(&req), &app.encryption_key,
+                                        "bors", "test").unwrap();
+    assert_eq!(project.github_repo_id, 42);
+    assert_eq!(project.github_access_token, "project-token");
+    assert_eq!(project.github_webhook_id, Some(1));
+
+    let requests = fake.requests();
+    let hook = requests.iter().find(|r| r.1.ends_with("/hooks")).unwrap();
+    assert!(hook.2.contains("http://localhost/webhook/github/bors/test"));
+    assert!(hook.2.contains(&project.github_webhook_secret));
+}
+
+#[test]
+fn add_project_again() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Post,
+                 "http://github.invalid/login/oauth/access_token",
+                 200, r#"{"access_token": "new-token"}"#);
+    fake.respond(http::Method::Get,
+                 "http://api.github.invalid/repos/bors/test/hooks",
+                 200, r#"[{
+                     "id": 7,
+                     "url": "http://api.github.invalid/repos/bors/test/hooks/7",
+                     "name": "web",
+                     "events": ["issue_comment", "issues", "pull_request",
+                                "pull_request_review",
+                                "pull_request_review_comment", "repository",
+                                "status"],
+                     "active": true,
+                     "config": {
+                         "url": "http://localhost/webhook/github/bors/test",
+                         "content_type": "json"
+                     },
+                     "updated_at": "2016-12-17T00:00:00Z",
+                     "created_at": "2016-12-17T00:00:00Z"
+                 }]"#);
+
+    let (tx, session, state) = start_adding(&middle, tx, &session);
+    let mut req = ::signed_in(tx, &session, Method::Get, "/authorize/github");
+    let old = Project::upsert(::tx(&req), &app.encryption_key, "bors",
+                              "test", 42, "old-token", "old-secret").unwrap();
+    old.set_github_webhook(::tx(&req), 7, "ok").unwrap();
+    req.with_query(&format!("code=repo-code&state={}", state));
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let project = Project::find_by_github_id(::tx(&req),
+                                             &app.encryption_key,
+                                             42).unwrap().unwrap();
+    assert_eq!(project.id, old.id);
+    assert_eq!(project.github_access_token, "new-token");
+    assert_eq!(project.github_webhook_secret, "old-secret");
+    assert_eq!(project.github_webhook_id, Some(7));
+    assert!(!fake.requests().iter().any(|r| {
+        r.0 != http::Method::Get && r.1.contains("/hooks")
+    }));
+}
+
+#[test]
+fn add_project_without_push_access() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Post,
+                 "http://github.invalid/login/oauth/access_token",
+                 200, r#"{"access_token": "project-token"}"#);
+    let (tx, session, state) = start_adding(&middle, tx, &session);
+    // Access is lost while the user is over on GitHub
+    fake.respond(http::Method::Get, "http://api.github.invalid/repos/bors/test",
+                 200, READ_ONLY_REPO);
+
+    let mut req = ::signed_in(tx, &session, Method::Get, "/authorize/github");
+    req.with_query(&format!("code=repo-code&state={}", state));
+    let resp = ::call(&middle, &mut req);
+    assert!(::body(resp).contains("you need push access"));
+
+    assert!(Project::find_by_name(::tx(&req), &app.encryption_key,
+                                  "bors", "test").is_err());
+    assert!(!fake.requests().iter().any(|r| r.1.ends_with("/hooks")));
+}
+
+#[test]
+fn add_project_hidden_from_user() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Post,
+                 "http://github.invalid/login/oauth/access_token",
+                 200, r#"{"access_token": "project-token"}"#);
+    let (tx, session, state) = start_adding(&middle, tx, &session);
+    fake.respond(http::Method::Get, "http://api.github.invalid/repos/bors/test",
+                 404, r#"{"message": "Not Found"}"#);
+
+    let mut req = ::signed_in(tx, &session, Method::Get, "/authorize/github");
+    req.with_query(&format!("code=repo-code&state={}", state));
+    let resp = ::call(&middle, &mut req);
+    assert!(::body(resp).contains("you need push access"));
+
+    assert!(Project::find_by_name(::tx(&req), &app.encryption_key,
+                                  "bors", "test").is_err());
+}
+
+#[test]
+fn add_travis_token() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Get, "http://api.travis.invalid/repos/bors/test",
+                 200, r#"{"repo": {"id": 7}}"#);
+    fake.respond(http::Method::Get, "http://api.travis.invalid/repos/7/settings",
+                 200, r#"{"settings": {"maximum_number_of_builds": 0}}"#);
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/add-travis-token");
+    ::project(&app, &req, "bors", "test");
+    req.with_body(b"token=travis-token");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let project = Project::find_by_name(::tx(&req), &app.encryption_key,
+                                        "bors", "test").unwrap();
+    assert_eq!(project.travis_access_token, Some("travis-token".to_string()));
+}
+
+#[test]
+fn add_invalid_travis_token() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Get, "http://api.travis.invalid/repos/bors/test",
+                 403, "access denied");
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/add-travis-token");
+    ::project(&app, &req, "bors", "test");
+    req.with_body(b"token=travis-token");
+    let resp = ::call(&middle, &mut req);
+    assert!(::body(resp).contains("travis token was invalid"));
+
+    let project = Project::find_by_name(::tx(&req), &app.encryption_key,
+                                        "bors", "test").unwrap();
+    assert_eq!(project.travis_access_token, None);
+}
+
+#[test]
+fn add_travis_token_requires_login() {
+    let (app, middle) = ::app();
+    let tx = db::Transaction::new(app.clone());
+    let (tx, session) = ::session(&middle, tx, "");
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/add-travis-token");
+    ::project(&app, &req, "bors", "test");
+    req.with_body(b"token=travis-token");
+    let resp = ::call(&middle, &mut req);
+    assert!(::body(resp).contains("you need to log in first"));
+
+    let project = Project::find_by_name(::tx(&req), &app.encryption_key,
+                                        "bors", "test").unwrap();
+    assert_eq!(project.travis_access_token, None);
+}
+
+#[test]
+fn sync_reviewers_follows_pages() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond_with_headers(
+        http::Method::Get,
+        "http://api.github.invalid/repos/bors/test/collaborators",
+        200,
+        &[("Link", "<http://api.github.invalid/repositories/42/collaborators?page=2>; \
+                    rel=\"next\"")],
+        r#"[{"login": "alice", "permissions": {"admin": true, "push": true, "pull": true}},
+            {"login": "carol", "permissions": {"admin": false, "push": false, "pull": true}}]"#);
+    fake.respond(
+        http::Method::Get,
+        "http://api.github.invalid/repositories/42/collaborators?page=2",
+        200,
+        r#"[{"login": "bob", "permissions": {"admin": false, "push": true, "pull": true}}]"#);
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/sync-reviewers");
+    let project = ::project(&app, &req, "bors", "test");
+    req.with_body(b"team=");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let reviewers = Reviewer::all(::tx(&req), project.id).unwrap();
+    let logins = reviewers.iter().map(|r| &r.login[..]).collect::<Vec<_>>();
+    assert_eq!(logins, ["alice", "bob"]);
+}
+
+#[test]
+fn queue_page() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Get, "/repos/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    {
+        let tx = ::tx(&req);
+        let mut prs = (1..5).map(|number| {
+            let payload = ::pull_request_event("opened", number,
+                                               &format!("{:040}", number));
+            let e: github::PullRequestEvent = json::decode(&payload).unwrap();
+            PullRequest::insert(tx, project.id, &e.pull_request).unwrap()
+        }).collect::<Vec<_>>();
+        // #4 is being tested, then come #3 and #2 which are approved, with
+        // #3 at a higher priority, and then #1 which isn't approved.
+        for pr in prs[1..].iter_mut() {
+            pr.approved_by = Some("bob".to_string());
+        }
+        prs[2].priority = 5;
+        prs[3].status = Status::Pending;
+        for pr in prs.iter() {
+            pr.update(tx).unwrap();
+        }
+    }
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 200);
+
+    let body = ::body(resp);
+    let row = |number: i32| {
+        body.find(&format!("/bors/test/pull/{}'", number)).unwrap()
+    };
+    assert!(row(4) < row(3));
+    assert!(row(3) < row(2));
+    assert!(row(2) < row(1));
+    assert!(body.contains("<tr class='pending'>"));
+    assert!(body.contains("<tr class='approved'>"));
+}
+
+#[test]
+fn reviewer_logins_ignore_case() {
+    let (app, _middle) = ::app();
+    let req = ::req(&app, Method::Post, "/repos/bors/test/reviewers");
+    let project = ::project(&app, &req, "bors", "test");
+    let tx = ::tx(&req);
+    Reviewer::set(tx, project.id, "Alice", Permission::Review).unwrap();
+    Reviewer::set(tx, project.id, "alice", Permission::Try).unwrap();
+
+    let reviewers = Reviewer::all(tx, project.id).unwrap();
+    assert_eq!(reviewers.len(), 1);
+    assert_eq!(reviewers[0].login, "alice");
+    assert_eq!(reviewers[0].permission, Permission::Try);
+
+    Reviewer::remove(tx, project.id, "ALICE").unwrap();
+    assert!(Reviewer::all(tx, project.id).unwrap().is_empty());
+}
+
+#[test]
+fn delete_project() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Delete,
+                 "http://api.github.invalid/repos/bors/test/hooks/1",
+                 204, "");
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/delete");
+    let project = ::project(&app, &req, "bors", "test");
+    Reviewer::set(::tx(&req), project.id, "alice",
+                  Permission::Review).unwrap();
+    Event::insert(::tx(&req), project.id, Provider::GitHub,
+                  "delivery-1", "ping", "{}").unwrap();
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    assert!(Project::find_by_name(::tx(&req), &app.encryption_key,
+                                  "bors", "test").is_err());
+    assert!(Reviewer::all(::tx(&req), project.id).unwrap().is_empty());
+    assert!(Event::recent(::tx(&req), project.id, None, None, None, 10)
+                  .unwrap().is_empty());
+    assert!(fake.requests().iter().any(|r| {
+        r.0 == http::Method::Delete && r.1.ends_with("/hooks/1")
+    }));
+}
+
+#[test]
+fn delete_project_with_missing_webhook() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Delete,
+                 "http://api.github.invalid/repos/bors/test/hooks/1",
+                 404, r#"{"message": "Not Found"}"#);
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/delete");
+    ::project(&app, &req, "bors", "test");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    assert!(Project::find_by_name(::tx(&req), &app.encryption_key,
+                                  "bors", "test").is_err());
+}
+
+#[test]
+fn check_webhook_repairs_disabled_hook() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Get,
+                 "http://api.github.invalid/repos/bors/test/hooks",
+                 200, r#"[{
+                     "id": 1,
+                     "url": "http://api.github.invalid/repos/bors/test/hooks/1",
+                     "name": "web",
+                     "events": ["pull_request"],
+                     "active": false,
+                     "config": {
+                         "url": "http://localhost/webhook/github/bors/test",
+                         "content_type": "json"
+                     },
+                     "last_response": {
+                         "code": 502,
+                         "status": "failed",
+                         "message": "Bad Gateway"
+                     },
+                     "updated_at": "2016-12-17T00:00:00Z",
+                     "created_at": "2016-12-17T00:00:00Z"
+                 }]"#);
+    fake.respond(http::Method::Patch,
+                 "http://api.github.invalid/repos/bors/test/hooks/1",
+                 200, HOOK);
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/check-webhook");
+    ::project(&app, &req, "bors", "test");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let requests = fake.requests();
+    let patch = requests.iter().find(|r| r.0 == http::Method::Patch).unwrap();
+    assert!(patch.2.contains("\"active\":true"));
+    assert!(patch.2.contains("issue_comment"));
+    assert!(patch.2.contains("webhook-secret"));
+
+    let project = Project::find_by_name(::tx(&req), &app.encryption_key,
+                                        "bors", "test").unwrap();
+    assert!(project.github_webhook_status.unwrap().contains("502 Bad Gateway"));
+}
+
+#[test]
+fn check_webhook_recreates_missing_hook() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Get,
+                 "http://api.github.invalid/repos/bors/test/hooks",
+                 200, "[]");
+    fake.respond(http::Method::Post,
+                 "http://api.github.invalid/repos/bors/test/hooks",
+                 201, &HOOK.replace("\"id\": 1", "\"id\": 2"));
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/check-webhook");
+    ::project(&app, &req, "bors", "test");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let project = Project::find_by_name(::tx(&req), &app.encryption_key,
+                                        "bors", "test").unwrap();
+    assert_eq!(project.github_webhook_id, Some(2));
+    assert!(project.github_webhook_status.unwrap().contains("re-created"));
+}
+
+#[test]
+fn check_webhook_failure_is_stored() {
+    let (fake, app, middle, tx, session) = ::maintainer();
+    fake.respond(http::Method::Get,
+                 "http://api.github.invalid/repos/bors/test/hooks",
+                 403, r#"{"message": "Must have admin rights to Repository."}"#);
+
+    let mut req = ::signed_in(tx, &session, Method::Post,
+                              "/repos/bors/test/check-webhook");
+    ::project(&app, &req, "bors", "test");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 200);
+    assert!(::body(resp).contains("failed to check the webhook"));
+
+    let project = Project::find_by_name(::tx(&req), &app.encryption_key,
+                                        "bors", "test").unwrap();
+    let status = project.github_webhook_status.unwrap();
+    assert!(status.starts_with("check failed"));
+    assert!(status.contains("admin rights"));
+}
+
+#[test]
+fn events_page() {
+    let (_, app, middle, tx, session) = ::maintainer();
+
+    let mut req = ::signed_in(tx, &session, Method::Get,
+                              "/repos/bors/test/events");
+    let project = ::project(&app, &req, "bors", "test");
+    Event::insert(::tx(&req), project.id, Provider::GitHub,
+                  "delivery-1", "ping", "{}").unwrap();
+    Event::insert(::tx(&req), project.id, Provider::Travis,
+                  "1-passed", "", "{}").unwrap();
+    req.with_query("provider=travis");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 200);
+    let body = ::body(resp);
+    assert!(body.contains("1-passed"));
+    assert!(!body.contains("delivery-1"));
+}
+
+#[test]
+fn reset_event() {
+    let (_, app, middle, tx, session) = ::maintainer();
+
+    let mut req = ::signed_in(tx, &session, Method::Get, "/");
+    let project = ::project(&app, &req, "bors", "test");
+    let event = Event::insert(::tx(&req), project.id, Provider::GitHub,
+                              "delivery-1", "ping", "{}").unwrap().unwrap();
+    event.set_state(::tx(&req), State::Failed).unwrap();
+
+    let tx = req.mut_extensions().pop::<db::Transaction>().unwrap();
+    let path = format!("/repos/bors/test/events/{}/reset", event.id);
+    let mut req = ::signed_in(tx, &session, Method::Post, &path);
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let tx = ::tx(&req);
+    let event = Event::find(tx, event.id).unwrap().unwrap();
+    assert_eq!(event.state, State::Unprocessed);
+    let rows = tx.query("SELECT processed_at IS NULL FROM events WHERE id = $1",
+                        &[&event.id]).unwrap();
+    assert!(rows.get(0).get::<_, bool>(0));
+}
+
+#[test]
+fn reset_event_of_another_project() {
+    let (_, app, middle, tx, session) = ::maintainer();
+
+    let mut req = ::signed_in(tx, &session, Method::Get, "/");
+    ::project(&app, &req, "bors", "test");
+    let other = ::project(&app, &req, "bors", "other");
+    let event = Event::insert(::tx(&req), other.id, Provider::GitHub,
+                              "delivery-1", "ping", "{}").unwrap().unwrap();
+    event.set_state(::tx(&req), State::Failed).unwrap();
+
+    let tx = req.mut_extensions().pop::<db::Transaction>().unwrap();
+    let path = format!("/repos/bors/test/events/{}/reset", event.id);
+    let mut req = ::signed_in(tx, &session, Method::Post, &path);
+    let resp = ::call(&middle, &mut req);
+    assert!(::body(resp).contains("event not found"));
+
+    let event = Event::find(::tx(&req), event.id).unwrap().unwrap();
+    assert_eq!(event.state, State::Failed);
+}
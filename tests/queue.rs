@@ -0,0 +1,396 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use conduit::Method;
+use conduit_test::MockRequest;
+
+use bors2::app::App;
+use bors2::http;
+use bors2::models::{Event, Permission, Project, Provider, PullRequest};
+use bors2::models::{Reviewer, Status};
+use bors2::queue;
+use bors2::worker;
+
+const API: &'static str = "http://api.github.invalid/repos/bors/test";
+
+/// The commit `master` is at.
+const MASTER: &'static str = "1111111111111111111111111111111111111111";
+
+/// The commit every merge creates.
+const MERGE: &'static str = "3333333333333333333333333333333333333333";
+
+/// Registers `bors/test` with Travis as its CI and `bob` as a reviewer, and
+/// has GitHub answer everything the queue asks of it.
+///
+/// Everything happens in the transaction of the returned request.
+fn setup() -> (::Fake, Arc<App>, MockRequest, Project) {
+    let fake = ::Fake::new();
+    let (app, _middle) = ::app_with(fake.clone());
+    let req = ::req(&app, Method::Get, "/");
+    let project = ::project(&app, &req, "bors", "test");
+    project.set_travis_token(::tx(&req), &app.encryption_key,
+                             "travis-token").unwrap();
+    Reviewer::set(::tx(&req), project.id, "bob", Permission::Review).unwrap();
+    let project = Project::find(::tx(&req), &app.encryption_key,
+                                project.id).unwrap();
+
+    let git_ref = format!(r#"{{"object": {{"sha": "{}"}}}}"#, MASTER);
+    fake.respond(http::Method::Get,
+                 &format!("{}/git/refs/heads/master", API), 200, &git_ref);
+    for branch in ["master", "auto", "try"].iter() {
+        fake.respond(http::Method::Patch,
+                     &format!("{}/git/refs/heads/{}", API, branch),
+                     200, &git_ref);
+    }
+    fake.respond(http::Method::Post, &format!("{}/merges", API),
+                 201, &format!(r#"{{"sha": "{}"}}"#, MERGE));
+    fake.respond(http::Method::Post, &format!("{}/statuses/*", API),
+                 201, r#"{
+                     "id": 1,
+                     "state": "pending",
+                     "context": "continuous-integration/bors"
+                 }"#);
+    fake.respond(http::Method::Post, &format!("{}/issues/*", API),
+                 201, r#"{
+                     "id": 1,
+                     "body": "",
+                     "user": {"id": 1, "login": "bors"}
+                 }"#);
+    (fake, app, req, project)
+}
+
+/// The head commit of pull request `number`.
+fn head(number: i32) -> String {
+    format!("{:040}", number)
+}
+
+/// Stores a GitHub `event` and handles it like the worker does.
+fn github(app: &App,
+          req: &MockRequest,
+          project: &Project,
+          event: &str,
+          payload: &str) {
+    static NEXT_DELIVERY: AtomicUsize = ATOMIC_USIZE_INIT;
+    let id = format!("delivery-{}",
+                     NEXT_DELIVERY.fetch_add(1, Ordering::SeqCst));
+    let tx = ::tx(req);
+    let event = Event::insert(tx, project.id, Provider::GitHub, &id, event,
+                              payload).unwrap().unwrap();
+    worker::handle(app, tx, &event).unwrap();
+}
+
+fn open(app: &App, req: &MockRequest, project: &Project, number: i32) {
+    let payload = ::pull_request_event("opened", number, &head(number));
+    github(app, req, project, "pull_request", &payload);
+}
+
+/// `user` commenting `body` on pull request `number`.
+fn comment(app: &App,
+           req: &MockRequest,
+           project: &Project,
+           number: i32,
+           user: &str,
+           body: &str) {
+    let payload = format!(r#"{{
+        "action": "created",
+        "issue": {{
+            "number": {number},
+            "title": "Change number {number}",
+            "user": {{"id": 2, "login": "alice"}},
+            "pull_request": {{"url": "{api}/pulls/{number}"}}
+        }},
+        "comment": {{
+            "id": 1,
+            "body": "{body}",
+            "user": {{"id": 3, "login": "{user}"}}
+        }}
+    }}"#, number = number, api = API, body = body, user = user);
+    github(app, req, project, "issue_comment", &payload);
+}
+
+/// Travis finishing a build of `sha` on `branch` in `state`.
+fn travis(app: &App,
+          req: &MockRequest,
+          project: &Project,
+          branch: &str,
+          sha: &str,
+          state: &str) {
+    static NEXT_BUILD: AtomicUsize = ATOMIC_USIZE_INIT;
+    let id = NEXT_BUILD.fetch_add(1, Ordering::SeqCst);
+    let payload = format!(r#"{{
+        "id": {id},
+        "number": "{id}",
+        "state": "{state}",
+        "branch": "{branch}",
+        "commit": "{sha}",
+        "build_url": "http://travis.invalid/builds/{id}"
+    }}"#, id = id, state = state, branch = branch, sha = sha);
+    let tx = ::tx(req);
+    let event = Event::insert(tx, project.id, Provider::Travis,
+                              &format!("{}-{}", id, state), "",
+                              &payload).unwrap().unwrap();
+    worker::handle(app, tx, &event).unwrap();
+}
+
+fn pull_request(req: &MockRequest,
+                project: &Project,
+                number: i32) -> PullRequest {
+    let pr = PullRequest::find_by_number(::tx(req), project.id, number);
+    pr.unwrap().unwrap()
+}
+
+/// The body of the request which fast-forwarded `master`, if any.
+fn fast_forward(fake: &::Fake) -> Option<String> {
+    let url = format!("{}/git/refs/heads/master", API);
+    fake.requests().into_iter().find(|r| {
+        r.0 == http::Method::Patch && r.1 == url
+    }).map(|r| r.2)
+}
+
+#[test]
+fn approved_pull_request_lands() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    comment(&app, &req, &project, 1, "bob", "@bors r+");
+    queue::process(&app, ::tx(&req), &project).unwrap();
+
+    let pr = pull_request(&req, &project, 1);
+    assert_eq!(pr.status, Status::Pending);
+    assert_eq!(pr.merge_sha, Some(MERGE.to_string()));
+    let requests = fake.requests();
+    let merge = requests.iter().find(|r| r.1.ends_with("/merges")).unwrap();
+    assert!(merge.2.contains("\"base\":\"auto\""));
+    assert!(merge.2.contains(&head(1)));
+    assert_eq!(fast_forward(&fake), None);
+
+    travis(&app, &req, &project, "auto", MERGE, "passed");
+    assert_eq!(pull_request(&req, &project, 1).status, Status::Success);
+    let update = fast_forward(&fake).unwrap();
+    assert!(update.contains(MERGE));
+    assert!(update.contains("\"force\":false"));
+}
+
+#[test]
+fn failed_build_does_not_land() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    comment(&app, &req, &project, 1, "bob", "@bors r+");
+    queue::process(&app, ::tx(&req), &project).unwrap();
+
+    travis(&app, &req, &project, "auto", MERGE, "failed");
+    assert_eq!(pull_request(&req, &project, 1).status, Status::Failure);
+    assert_eq!(fast_forward(&fake), None);
+    assert!(fake.requests().iter().any(|r| {
+        r.1.ends_with("/issues/1/comments") && r.2.contains("Test failed")
+    }));
+}
+
+#[test]
+fn unapproved_during_build() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    comment(&app, &req, &project, 1, "bob", "@bors r+");
+    queue::process(&app, ::tx(&req), &project).unwrap();
+
+    comment(&app, &req, &project, 1, "bob", "@bors r-");
+    assert_eq!(pull_request(&req, &project, 1).status, Status::Idle);
+    travis(&app, &req, &project, "auto", MERGE, "passed");
+    assert_eq!(pull_request(&req, &project, 1).status, Status::Idle);
+    assert_eq!(fast_forward(&fake), None);
+}
+
+#[test]
+fn closed_during_build() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    comment(&app, &req, &project, 1, "bob", "@bors r+");
+    queue::process(&app, ::tx(&req), &project).unwrap();
+
+    let payload = ::pull_request_event("closed", 1, &head(1));
+    github(&app, &req, &project, "pull_request", &payload);
+    travis(&app, &req, &project, "auto", MERGE, "passed");
+    let pr = pull_request(&req, &project, 1);
+    assert!(!pr.open);
+    assert_eq!(pr.status, Status::Idle);
+    assert_eq!(fast_forward(&fake), None);
+}
+
+#[test]
+fn highest_priority_is_tested_first() {
+    let (fake, app, req, project) = setup();
+    for number in 1..4 {
+        open(&app, &req, &project, number);
+    }
+    comment(&app, &req, &project, 1, "bob", "@bors r+");
+    comment(&app, &req, &project, 2, "bob", "@bors r+ p=5");
+    comment(&app, &req, &project, 3, "bob", "@bors r+");
+    queue::process(&app, ::tx(&req), &project).unwrap();
+
+    assert_eq!(pull_request(&req, &project, 2).status, Status::Pending);
+    let requests = fake.requests();
+    let merge = requests.iter().find(|r| r.1.ends_with("/merges")).unwrap();
+    assert!(merge.2.contains(&head(2)));
+
+    // Ties go to the oldest pull request
+    travis(&app, &req, &project, "auto", MERGE, "passed");
+    queue::process(&app, ::tx(&req), &project).unwrap();
+    assert_eq!(pull_request(&req, &project, 1).status, Status::Pending);
+    assert_eq!(pull_request(&req, &project, 3).status, Status::Idle);
+}
+
+#[test]
+fn failed_try_build_keeps_auto_build() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    open(&app, &req, &project, 2);
+    comment(&app, &req, &project, 1, "bob", "@bors r+");
+    comment(&app, &req, &project, 2, "bob", "@bors try");
+    fake.respond(http::Method::Patch, &format!("{}/git/refs/heads/try", API),
+                 403, r#"{"message": "Resource not accessible"}"#);
+    assert!(queue::process(&app, ::tx(&req), &project).is_err());
+
+    let pr = pull_request(&req, &project, 1);
+    assert_eq!(pr.status, Status::Pending);
+    assert_eq!(pr.merge_sha, Some(MERGE.to_string()));
+    let pr = pull_request(&req, &project, 2);
+    assert!(pr.try_requested);
+    assert_eq!(pr.try_status, Status::Idle);
+}
+
+#[test]
+fn failed_auto_build_keeps_try_build() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    open(&app, &req, &project, 2);
+    comment(&app, &req, &project, 1, "bob", "@bors r+");
+    comment(&app, &req, &project, 2, "bob", "@bors try");
+    fake.respond(http::Method::Patch, &format!("{}/git/refs/heads/auto", API),
+                 403, r#"{"message": "Resource not accessible"}"#);
+    assert!(queue::process(&app, ::tx(&req), &project).is_err());
+
+    assert_eq!(pull_request(&req, &project, 1).status, Status::Idle);
+    let pr = pull_request(&req, &project, 2);
+    assert_eq!(pr.try_status, Status::Pending);
+    assert_eq!(pr.try_sha, Some(MERGE.to_string()));
+}
+
+/// Opens pull requests 1 and 2 and approves them as rollups, with GitHub
+/// answering as if the rollup branch didn't exist yet.
+fn rollup_setup(fake: &::Fake,
+                app: &App,
+                req: &MockRequest,
+                project: &Project) {
+    for number in 1..3 {
+        open(app, req, project, number);
+        comment(app, req, project, number, "bob", "@bors r+ rollup");
+    }
+    fake.respond(http::Method::Patch,
+                 &format!("{}/git/refs/heads/rollup-*", API),
+                 422, r#"{"message": "Reference does not exist"}"#);
+    fake.respond(http::Method::Post, &format!("{}/git/refs", API),
+                 201, &format!(r#"{{"object": {{"sha": "{}"}}}}"#, MASTER));
+}
+
+#[test]
+fn rollup() {
+    let (fake, app, req, project) = setup();
+    rollup_setup(&fake, &app, &req, &project);
+    fake.respond(http::Method::Post, &format!("{}/pulls", API),
+                 201, &::pull_request(3, "open", &head(3)));
+    let rollup = queue::rollup(&app, ::tx(&req), &project).unwrap().unwrap();
+    assert_eq!(rollup.number, 3);
+
+    for number in 1..3 {
+        let pr = pull_request(&req, &project, number);
+        assert_eq!(pr.rollup_id, Some(rollup.id));
+    }
+    let requests = fake.requests();
+    let branch = requests.iter().find(|r| {
+        r.0 == http::Method::Post && r.1.ends_with("/git/refs")
+    }).unwrap();
+    assert!(branch.2.contains("refs/heads/rollup-"));
+    let opened = requests.iter().find(|r| r.1.ends_with("/pulls")).unwrap();
+    assert!(opened.2.contains("#1"));
+    assert!(opened.2.contains("#2"));
+
+    // Only the rollup itself goes through the queue now
+    let next = PullRequest::next_in_queue(::tx(&req), project.id).unwrap();
+    assert!(next.is_none());
+}
+
+#[test]
+fn rollup_which_cannot_be_opened() {
+    let (fake, app, req, project) = setup();
+    rollup_setup(&fake, &app, &req, &project);
+    fake.respond(http::Method::Post, &format!("{}/pulls", API),
+                 422, r#"{"message": "Validation Failed"}"#);
+    fake.respond(http::Method::Delete,
+                 &format!("{}/git/refs/heads/rollup-*", API), 204, "");
+    assert!(queue::rollup(&app, ::tx(&req), &project).is_err());
+
+    let requests = fake.requests();
+    let created = requests.iter().find(|r| {
+        r.0 == http::Method::Post && r.1.ends_with("/git/refs")
+    }).unwrap();
+    let deleted = requests.iter().find(|r| {
+        r.0 == http::Method::Delete
+    }).unwrap();
+    let branch = deleted.1.rsplit('/').next().unwrap();
+    assert!(branch.starts_with("rollup-"));
+    assert!(created.2.contains(branch));
+    assert_eq!(pull_request(&req, &project, 1).rollup_id, None);
+}
+
+#[test]
+fn rollup_landed() {
+    let (fake, app, req, project) = setup();
+    rollup_setup(&fake, &app, &req, &project);
+    fake.respond(http::Method::Post, &format!("{}/pulls", API),
+                 201, &::pull_request(3, "open", &head(3)));
+    fake.respond(http::Method::Delete,
+                 &format!("{}/git/refs/heads/change-3", API), 204, "");
+    queue::rollup(&app, ::tx(&req), &project).unwrap().unwrap();
+    comment(&app, &req, &project, 3, "bob", "@bors r+");
+    queue::process(&app, ::tx(&req), &project).unwrap();
+    assert_eq!(pull_request(&req, &project, 3).status, Status::Pending);
+
+    travis(&app, &req, &project, "auto", MERGE, "passed");
+    for number in 1..4 {
+        assert_eq!(pull_request(&req, &project, number).status,
+                   Status::Success);
+    }
+    let requests = fake.requests();
+    for number in 1..3 {
+        let url = format!("{}/issues/{}/comments", API, number);
+        assert!(requests.iter().any(|r| {
+            r.1 == url && r.2.contains("Merged in rollup #3")
+        }));
+    }
+    assert!(requests.iter().any(|r| r.0 == http::Method::Delete));
+}
+
+#[test]
+fn approval_from_non_reviewer() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    comment(&app, &req, &project, 1, "mallory", "@bors r+");
+
+    assert_eq!(pull_request(&req, &project, 1).approved_by, None);
+    let url = format!("{}/issues/1/comments", API);
+    let requests = fake.requests();
+    let reply = requests.iter().find(|r| r.1 == url).unwrap();
+    assert!(reply.2.contains("@mallory, you don't have permission"));
+}
+
+#[test]
+fn approval_from_non_reviewer_without_reply() {
+    let (fake, app, req, project) = setup();
+    open(&app, &req, &project, 1);
+    fake.respond(http::Method::Post, &format!("{}/issues/1/comments", API),
+                 502, "Bad Gateway");
+    // Still handled, without the event failing
+    comment(&app, &req, &project, 1, "mallory", "@bors r+ p=10");
+    let pr = pull_request(&req, &project, 1);
+    assert_eq!(pr.approved_by, None);
+    assert_eq!(pr.priority, 0);
+}
@@ -0,0 +1,66 @@
+use conduit::{Method, Request};
+
+use bors2::db;
+
+#[test]
+fn logout() {
+    let (_, _, middle, tx, session) = ::maintainer();
+    let mut req = ::signed_in(tx, &session, Method::Post, "/logout");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 302);
+
+    let tx = req.mut_extensions().pop::<db::Transaction>().unwrap();
+    let cookie = ::cookies(&resp);
+    let mut req = ::signed_in(tx, &::Session {
+        cookie: cookie,
+        csrf_token: String::new(),
+    }, Method::Get, "/");
+    assert!(!::body(::call(&middle, &mut req)).contains("logged in as"));
+}
+
+#[test]
+fn logout_requires_csrf_token() {
+    let (_, _, middle, tx, session) = ::maintainer();
+    let mut req = ::signed_in(tx, &session, Method::Post, "/logout");
+    req.with_query("csrf_token=wrong");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 400);
+
+    let tx = req.mut_extensions().pop::<db::Transaction>().unwrap();
+    let mut req = ::signed_in(tx, &session, Method::Get, "/");
+    assert!(::body(::call(&middle, &mut req)).contains("logged in as alice"));
+}
+
+#[test]
+fn logout_is_not_a_link() {
+    let (_, _, middle, tx, session) = ::maintainer();
+    let mut req = ::signed_in(tx, &session, Method::Get, "/logout");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 404);
+}
+
+#[test]
+fn forms_require_csrf_token() {
+    let (fake, _, middle, tx, session) = ::maintainer();
+    let mut req = ::signed_in(tx, &session, Method::Post, "/repos");
+    req.with_query("");
+    req.with_body(b"repo=bors/test");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 400);
+    assert!(fake.requests().iter().all(|r| !r.1.contains("/repos/bors/test")));
+}
+
+#[test]
+fn oauth_state_must_match_session() {
+    let (_, _, middle, tx, session) = ::maintainer();
+    let mut req = ::signed_in(tx, &session, Method::Get, "/authorize/github");
+    req.with_query("code=repo-code&state=bors/test");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 400);
+
+    let tx = req.mut_extensions().pop::<db::Transaction>().unwrap();
+    let mut req = ::signed_in(tx, &session, Method::Get, "/authorize/github");
+    req.with_query("code=repo-code&state=forged:bors/test");
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 400);
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use openssl::crypto::hash::{self, Type};
+use openssl::crypto::rsa::RSA;
+
+use bors2::http;
+use bors2::travis::KeyCache;
+
+const CONFIG_URL: &'static str = "http://api.travis.invalid/config";
+const PAYLOAD: &'static [u8] = b"{\"id\": 1}";
+
+/// Has Travis sign its webhooks with `key` from now on.
+fn serve_key(fake: &::Fake, key: &RSA) {
+    let pem = String::from_utf8(key.public_key_to_pem().unwrap()).unwrap();
+    let body = format!(r#"{{"config": {{"notifications": {{"webhook": {{
+        "public_key": "{}"
+    }}}}}}}}"#, pem.replace("\n", "\\n"));
+    fake.respond(http::Method::Get, CONFIG_URL, 200, &body);
+}
+
+fn sign(key: &RSA) -> Vec<u8> {
+    key.sign(Type::SHA1, &hash::hash(Type::SHA1, PAYLOAD).unwrap()).unwrap()
+}
+
+fn fetches(fake: &::Fake) -> usize {
+    fake.requests().iter().filter(|r| r.1 == CONFIG_URL).count()
+}
+
+fn cache(ttl: u64, min_refresh: u64) -> KeyCache {
+    KeyCache::with_ttl(vec!["http://api.travis.invalid".to_string()],
+                       Duration::from_secs(ttl),
+                       Duration::from_secs(min_refresh))
+}
+
+#[test]
+fn keys_are_cached() {
+    let fake = ::Fake::new();
+    let key = RSA::generate(2048).unwrap();
+    serve_key(&fake, &key);
+    let keys = cache(60 * 60, 60);
+
+    keys.verify(&fake, PAYLOAD, &sign(&key)).unwrap();
+    keys.verify(&fake, PAYLOAD, &sign(&key)).unwrap();
+    assert_eq!(fetches(&fake), 1);
+}
+
+#[test]
+fn keys_expire() {
+    let fake = ::Fake::new();
+    let key = RSA::generate(2048).unwrap();
+    serve_key(&fake, &key);
+    let keys = cache(0, 0);
+
+    keys.verify(&fake, PAYLOAD, &sign(&key)).unwrap();
+    keys.verify(&fake, PAYLOAD, &sign(&key)).unwrap();
+    assert_eq!(fetches(&fake), 2);
+}
+
+#[test]
+fn rotated_key_is_refetched() {
+    let fake = ::Fake::new();
+    let old = RSA::generate(2048).unwrap();
+    let new = RSA::generate(2048).unwrap();
+    serve_key(&fake, &old);
+    let keys = cache(60 * 60, 0);
+    keys.verify(&fake, PAYLOAD, &sign(&old)).unwrap();
+
+    serve_key(&fake, &new);
+    keys.verify(&fake, PAYLOAD, &sign(&new)).unwrap();
+    assert_eq!(fetches(&fake), 2);
+}
+
+#[test]
+fn bad_signatures_dont_refetch_too_often() {
+    let fake = ::Fake::new();
+    let key = RSA::generate(2048).unwrap();
+    let other = RSA::generate(2048).unwrap();
+    serve_key(&fake, &key);
+    let keys = cache(60 * 60, 60);
+    keys.verify(&fake, PAYLOAD, &sign(&key)).unwrap();
+
+    assert!(keys.verify(&fake, PAYLOAD, &sign(&other)).is_err());
+    assert!(keys.verify(&fake, PAYLOAD, &sign(&other)).is_err());
+    assert_eq!(fetches(&fake), 1);
+}
@@ -0,0 +1,318 @@
+use conduit::{Handler, Method};
+use conduit_test::MockRequest;
+use openssl::crypto::hash::Type;
+use openssl::crypto::hmac;
+use rustc_serialize::hex::ToHex;
+
+use bors2::http;
+use bors2::models::{Event, Project, Provider};
+use bors2::worker;
+
+const PAYLOAD: &'static str = include_str!("fixtures/pull_request.json");
+
+fn sign(ty: Type, secret: &str, body: &str) -> String {
+    hmac::hmac(ty, secret.as_bytes(), body.as_bytes()).unwrap().to_hex()
+}
+
+fn events(req: &::conduit::Request, project_id: i32) -> i64 {
+    let tx = ::tx(req);
+    let rows = tx.query("SELECT COUNT(*) FROM events WHERE project_id = $1",
+                        &[&project_id]).unwrap();
+    rows.get(0).get(0)
+}
+
+#[test]
+fn sha256_signature() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    let signature = format!("sha256={}",
+                            sign(Type::SHA256, "webhook-secret", PAYLOAD));
+    req.header("X-GitHub-Event", "pull_request")
+       .header("X-GitHub-Delivery", "delivery-1")
+       .header("X-Hub-Signature-256", &signature)
+       .with_body(PAYLOAD.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 200);
+    assert_eq!(events(&req, project.id), 1);
+}
+
+#[test]
+fn sha1_signature() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    let signature = format!("sha1={}",
+                            sign(Type::SHA1, "webhook-secret", PAYLOAD));
+    req.header("X-GitHub-Event", "pull_request")
+       .header("X-GitHub-Delivery", "delivery-1")
+       .header("X-Hub-Signature", &signature)
+       .with_body(PAYLOAD.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 200);
+    assert_eq!(events(&req, project.id), 1);
+}
+
+#[test]
+fn duplicate_delivery() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    let first = Event::insert(::tx(&req), project.id, Provider::GitHub,
+                              "delivery-1", "pull_request", PAYLOAD).unwrap();
+    assert!(first.is_some());
+
+    let signature = format!("sha256={}",
+                            sign(Type::SHA256, "webhook-secret", PAYLOAD));
+    req.header("X-GitHub-Event", "pull_request")
+       .header("X-GitHub-Delivery", "delivery-1")
+       .header("X-Hub-Signature-256", &signature)
+       .with_body(PAYLOAD.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 200);
+    assert_eq!(events(&req, project.id), 1);
+}
+
+#[test]
+fn same_id_on_another_project() {
+    let (app, _middle) = ::app();
+    let req = ::req(&app, Method::Post, "/webhook/travis");
+    let org = ::project(&app, &req, "bors", "test");
+    let com = ::project(&app, &req, "bors", "private");
+    let tx = ::tx(&req);
+    for project in [&org, &com].iter() {
+        let event = Event::insert(tx, project.id, Provider::Travis, "1-passed",
+                                  "", "{}").unwrap();
+        assert!(event.is_some());
+    }
+    assert_eq!(events(&req, org.id), 1);
+    assert_eq!(events(&req, com.id), 1);
+}
+
+#[test]
+fn invalid_signature() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    let signature = format!("sha256={}",
+                            sign(Type::SHA256, "wrong-secret", PAYLOAD));
+    req.header("X-GitHub-Event", "pull_request")
+       .header("X-GitHub-Delivery", "delivery-1")
+       .header("X-Hub-Signature-256", &signature)
+       .with_body(PAYLOAD.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 401);
+    assert_eq!(events(&req, project.id), 0);
+}
+
+#[test]
+fn short_signature() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    req.header("X-GitHub-Event", "pull_request")
+       .header("X-GitHub-Delivery", "delivery-1")
+       .header("X-Hub-Signature-256", "sha256=abc")
+       .with_body(PAYLOAD.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 401);
+    assert_eq!(events(&req, project.id), 0);
+}
+
+#[test]
+fn missing_signature() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    ::project(&app, &req, "bors", "test");
+    req.header("X-GitHub-Event", "pull_request")
+       .header("X-GitHub-Delivery", "delivery-1")
+       .with_body(PAYLOAD.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 401);
+}
+
+#[test]
+fn missing_event() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    ::project(&app, &req, "bors", "test");
+    let signature = format!("sha256={}",
+                            sign(Type::SHA256, "webhook-secret", PAYLOAD));
+    req.header("X-GitHub-Delivery", "delivery-1")
+       .header("X-Hub-Signature-256", &signature)
+       .with_body(PAYLOAD.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 400);
+    assert!(::body(resp).contains("X-GitHub-Event"));
+}
+
+#[test]
+fn renamed_repository() {
+    let (app, middle) = ::app();
+    let mut req = ::req(&app, Method::Post, "/webhook/github/bors/old-name");
+    let project = ::project(&app, &req, "bors", "test");
+    let payload = format!(r#"{{
+        "action": "renamed",
+        "repository": {{
+            "id": {},
+            "name": "test",
+            "owner": {{"id": 2, "login": "bors"}}
+        }}
+    }}"#, project.github_repo_id);
+    let signature = format!("sha256={}",
+                            sign(Type::SHA256, "webhook-secret", &payload));
+    req.header("X-GitHub-Event", "repository")
+       .header("X-GitHub-Delivery", "delivery-1")
+       .header("X-Hub-Signature-256", &signature)
+       .with_body(payload.as_bytes());
+    let resp = ::call(&middle, &mut req);
+    assert_eq!(resp.status.0, 200);
+    assert_eq!(events(&req, project.id), 1);
+}
+
+#[test]
+fn renamed_repository_is_followed() {
+    let fake = ::Fake::new();
+    let (app, _middle) = ::app_with(fake.clone());
+    let req = ::req(&app, Method::Post, "/webhook/github/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    fake.respond(http::Method::Get,
+                 "http://api.github.invalid/repos/bors/new-name/hooks",
+                 200, r#"[{
+                     "id": 1,
+                     "url": "http://api.github.invalid/repos/bors/new-name/hooks/1",
+                     "name": "web",
+                     "events": ["issue_comment", "issues", "pull_request",
+                                "pull_request_review",
+                                "pull_request_review_comment", "repository",
+                                "status"],
+                     "active": true,
+                     "config": {
+                         "url": "http://localhost/webhook/github/bors/test",
+                         "content_type": "json"
+                     },
+                     "updated_at": "2016-12-17T00:00:00Z",
+                     "created_at": "2016-12-17T00:00:00Z"
+                 }]"#);
+    fake.respond(http::Method::Patch,
+                 "http://api.github.invalid/repos/bors/new-name/hooks/1",
+                 200, r#"{
+                     "id": 1,
+                     "url": "http://api.github.invalid/repos/bors/new-name/hooks/1",
+                     "name": "web",
+                     "events": [],
+                     "active": true,
+                     "config": {},
+                     "updated_at": "2016-12-17T00:00:00Z",
+                     "created_at": "2016-12-17T00:00:00Z"
+                 }"#);
+
+    let payload = format!(r#"{{
+        "action": "renamed",
+        "repository": {{
+            "id": {},
+            "name": "new-name",
+            "owner": {{"id": 2, "login": "bors"}}
+        }}
+    }}"#, project.github_repo_id);
+    let tx = ::tx(&req);
+    let event = Event::insert(tx, project.id, Provider::GitHub, "delivery-1",
+                              "repository", &payload).unwrap().unwrap();
+    worker::handle(&app, tx, &event).unwrap();
+
+    let project = Project::find(tx, &app.encryption_key, project.id).unwrap();
+    assert_eq!(project.repo_user, "bors");
+    assert_eq!(project.repo_name, "new-name");
+
+    let requests = fake.requests();
+    let patch = requests.iter().find(|r| r.0 == http::Method::Patch).unwrap();
+    assert!(patch.2.contains("http://localhost/webhook/github/bors/new-name"));
+}
+
+fn appveyor_notification() -> &'static str {
+    r#"{
+        "eventName": "build_success",
+        "eventData": {
+            "accountName": "bors",
+            "projectSlug": "test",
+            "repositoryName": "bors/test",
+            "buildId": 7,
+            "buildVersion": "1.0.7",
+            "branch": "auto",
+            "commitId": "3333333333333333333333333333333333333333",
+            "status": "Success",
+            "passed": true,
+            "failed": false,
+            "buildUrl": "http://appveyor.invalid/project/bors/test/build/1.0.7"
+        }
+    }"#
+}
+
+/// The build `appveyor_notification` is about, as AppVeyor describes it.
+fn appveyor_build(repo: &str, branch: &str) -> String {
+    format!(r#"{{
+        "project": {{
+            "projectId": 1,
+            "accountName": "bors",
+            "repositoryType": "gitHub",
+            "slug": "test",
+            "name": "test",
+            "repositoryName": "{}"
+        }},
+        "build": {{
+            "buildId": 7,
+            "version": "1.0.7",
+            "branch": "{}",
+            "commitId": "3333333333333333333333333333333333333333",
+            "status": "success"
+        }}
+    }}"#, repo, branch)
+}
+
+/// Sends `appveyor_notification` to `bors/test`, with AppVeyor answering
+/// `build` when asked about it.
+fn appveyor_webhook(build: &str) -> (MockRequest, i32, Option<u32>) {
+    let fake = ::Fake::new();
+    let (app, middle) = ::app_with(fake.clone());
+    let mut req = ::req(&app, Method::Post, "/webhook/appveyor/bors/test");
+    let project = ::project(&app, &req, "bors", "test");
+    project.set_appveyor_token(::tx(&req), &app.encryption_key,
+                               "appveyor-token").unwrap();
+    fake.respond(http::Method::Get,
+                 "http://appveyor.invalid/api/projects/bors/test/build/1.0.7",
+                 200, build);
+    req.with_body(appveyor_notification().as_bytes());
+    let status = middle.call(&mut req).ok().map(|resp| resp.status.0);
+    (req, project.id, status)
+}
+
+#[test]
+fn appveyor_stores_the_fetched_build() {
+    let (req, project_id, status) =
+        appveyor_webhook(&appveyor_build("bors/test", "auto"));
+    assert_eq!(status, Some(200));
+
+    let tx = ::tx(&req);
+    let rows = tx.query("SELECT event FROM events WHERE project_id = $1",
+                        &[&project_id]).unwrap();
+    let stored: String = rows.get(0).get(0);
+    assert!(stored.contains("\"repositoryName\":\"bors/test\""));
+    assert!(!stored.contains("buildUrl"));
+}
+
+#[test]
+fn appveyor_build_of_another_repository() {
+    let (req, project_id, status) =
+        appveyor_webhook(&appveyor_build("mallory/test", "auto"));
+    assert_eq!(status, None);
+    assert_eq!(events(&req, project_id), 0);
+}
+
+#[test]
+fn appveyor_build_on_another_branch() {
+    // The notification claims `auto`, but AppVeyor built master.
+    let (req, project_id, status) =
+        appveyor_webhook(&appveyor_build("bors/test", "master"));
+    assert_eq!(status, Some(400));
+    assert_eq!(events(&req, project_id), 0);
+}